@@ -0,0 +1,415 @@
+//! Per client send state.
+
+use crate::{
+    config::SlowClientPolicy,
+    filter::{self, Filter},
+    gap,
+    protocol::Request,
+    server::ServerState,
+    stats::ClientCounters,
+    transport::Transport,
+};
+use std::{
+    collections::VecDeque,
+    io, mem,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// The longest request line kept, so a client can't grow the buffer forever.
+const MAX_REQUEST_BYTES: usize = 256;
+
+/// A transport the writer thread can own.
+pub(crate) type BoxTransport = Box<dyn Transport<Error = io::Error> + Send>;
+
+/// Whether the bytes a client has been sent end on a frame boundary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// The client may be partway through a frame, either because it just
+    /// connected or because a frame was cut short. A frame separator must be
+    /// sent before the next frame so its decoder discards the partial frame.
+    Lost,
+    /// The queued frames follow on from a complete frame.
+    Framed,
+}
+
+/// An encoded frame waiting to be sent.
+struct Queued {
+    encoded: Vec<u8>,
+    /// `1` for a log frame, and `0` for a control frame or separator.
+    frames: u32,
+    /// For a frame saying frames were dropped, how many.
+    dropped: u32,
+    /// Whether the frame is never skipped, as it is a control frame or part
+    /// of the history replayed when the client connected.
+    pinned: bool,
+}
+
+/// A connected client, and the encoded frames waiting to be sent to it.
+///
+/// Frames are only ever queued whole, and a frame that has been partly
+/// written is either finished or followed by a frame separator, so clients
+/// always see a well formed stream.
+pub(crate) struct Client {
+    transport: BoxTransport,
+    queue: VecDeque<Queued>,
+    queued_bytes: usize,
+    /// How many of the queued bytes are in pinned frames.
+    pinned_bytes: usize,
+    /// How much of the front frame has been written.
+    offset: usize,
+    framing: Framing,
+    /// When the client last accepted any bytes, while it has some queued.
+    progress: Instant,
+    /// The start of a request line that hasn't been terminated yet.
+    inbound: Vec<u8>,
+    /// The next defmt table frame to send, while the table is being sent.
+    pub(crate) table_chunk: Option<usize>,
+    /// Which log frames the client has asked for.
+    filter: Filter,
+    /// The server that accepted the client, if it was accepted rather than
+    /// added as a transport.
+    server: Option<Arc<ServerState>>,
+    /// The most bytes queued before the slow client policy applies, or
+    /// `None` for the writer's default.
+    queue_limit: Option<usize>,
+    slow_client_policy: SlowClientPolicy,
+    counters: Arc<ClientCounters>,
+}
+
+impl Client {
+    /// Returns a client sending to `transport`, with the address of its peer
+    /// if a server accepted it.
+    pub(crate) fn new(transport: BoxTransport, peer: Option<String>) -> Self {
+        Self {
+            transport,
+            queue: VecDeque::new(),
+            queued_bytes: 0,
+            pinned_bytes: 0,
+            offset: 0,
+            framing: Framing::Lost,
+            progress: Instant::now(),
+            inbound: Vec::new(),
+            table_chunk: None,
+            filter: Filter::default(),
+            server: None,
+            queue_limit: None,
+            slow_client_policy: SlowClientPolicy::default(),
+            counters: ClientCounters::register(peer),
+        }
+    }
+
+    /// Queues an encoded log frame to be sent.
+    pub(crate) fn push(&mut self, encoded: &[u8]) {
+        self.enqueue(encoded.to_vec(), 1, 0, false);
+    }
+
+    /// Queues a frame from the history, replayed when the client connects.
+    pub(crate) fn push_history(&mut self, encoded: &[u8]) {
+        self.enqueue(encoded.to_vec(), 1, 0, true);
+    }
+
+    /// Queues an encoded control frame to be sent.
+    pub(crate) fn push_control(&mut self, encoded: &[u8]) {
+        self.enqueue(encoded.to_vec(), 0, 0, true);
+    }
+
+    /// Queues `report`, an encoded frame saying `frames` frames were dropped
+    /// before reaching the client.
+    pub(crate) fn push_dropped(&mut self, frames: u32, report: &[u8]) {
+        self.counters.dropped(frames);
+        self.enqueue(report.to_vec(), 0, frames, false);
+    }
+
+    fn enqueue(&mut self, encoded: Vec<u8>, frames: u32, dropped: u32, pinned: bool) {
+        if self.queue.is_empty() {
+            self.progress = Instant::now();
+        }
+
+        if self.framing == Framing::Lost {
+            self.queue.push_back(separator());
+            self.queued_bytes += 1;
+            self.pinned_bytes += 1;
+            self.framing = Framing::Framed;
+        }
+
+        self.queued_bytes += encoded.len();
+        if pinned {
+            self.pinned_bytes += encoded.len();
+        }
+        self.queue.push_back(Queued {
+            encoded,
+            frames,
+            dropped,
+            pinned,
+        });
+    }
+
+    /// Returns `true` if the client wants a frame with this raw content.
+    pub(crate) fn wants(&self, raw: &[u8]) -> bool {
+        self.filter.allows(raw)
+    }
+
+    /// Sets which log frames the client is sent from now on.
+    pub(crate) fn set_filter(&mut self, filter: Filter) {
+        if !filter.is_empty() {
+            filter::load_frames();
+        }
+        self.filter = filter;
+    }
+
+    /// Sets how many bytes may be queued for the client, and what happens
+    /// when it falls further behind.
+    pub(crate) fn set_limits(&mut self, queue_limit: Option<usize>, policy: SlowClientPolicy) {
+        self.queue_limit = queue_limit;
+        self.slow_client_policy = policy;
+    }
+
+    /// Returns the most bytes that may be queued for the client, where
+    /// `default` is the limit for clients without one of their own.
+    pub(crate) fn queue_limit(&self, default: usize) -> usize {
+        self.queue_limit.unwrap_or(default)
+    }
+
+    pub(crate) fn slow_client_policy(&self) -> SlowClientPolicy {
+        self.slow_client_policy
+    }
+
+    /// Records the server that accepted the client.
+    pub(crate) fn set_server(&mut self, server: Arc<ServerState>) {
+        self.server = Some(server);
+    }
+
+    /// Returns `true` if the server that accepted the client is shutting
+    /// down, so the client should be disconnected.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.server
+            .as_ref()
+            .is_some_and(|server| server.is_closing())
+    }
+
+    /// Returns the number of bytes waiting to be sent.
+    pub(crate) fn queued_bytes(&self) -> usize {
+        self.queued_bytes - self.offset
+    }
+
+    /// Returns the number of bytes waiting to be sent that could be skipped,
+    /// which is what the queue limit applies to. Control frames and the
+    /// history don't count, so a client isn't cut off as it connects.
+    pub(crate) fn backlog(&self) -> usize {
+        let front_pinned = self.queue.front().is_some_and(|queued| queued.pinned);
+        let written = if front_pinned { 0 } else { self.offset };
+        self.queued_bytes - self.pinned_bytes - written
+    }
+
+    /// Returns `true` if nothing is waiting to be sent.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Returns `true` if queued bytes have gone unsent for `timeout`.
+    pub(crate) fn is_stalled(&self, timeout: Duration) -> bool {
+        !self.queue.is_empty() && self.progress.elapsed() > timeout
+    }
+
+    /// Discards the queued frames that aren't pinned, cutting short any
+    /// partly written frame, and queues a frame saying how many were dropped
+    /// after the rest.
+    ///
+    /// Returns the number of log frames discarded. Drops that discarded
+    /// reports were to tell the client about are added to the new report,
+    /// but not returned, as they were counted before.
+    pub(crate) fn skip_queued(&mut self) -> u32 {
+        let (mut skipped, mut unreported) = (0_u32, 0_u32);
+        let mut cut = false;
+        let mut first = true;
+        let offset = self.offset;
+        self.queue.retain(|queued| {
+            let front = mem::take(&mut first);
+            if queued.pinned {
+                return true;
+            }
+            cut |= front && offset > 0;
+            skipped = skipped.saturating_add(queued.frames);
+            unreported = unreported.saturating_add(queued.dropped);
+            false
+        });
+
+        if cut {
+            // The decoder discards the rest of the cut frame at the separator.
+            self.queue.push_front(separator());
+            self.offset = 0;
+        }
+        self.queued_bytes = self.queue.iter().map(|queued| queued.encoded.len()).sum();
+        self.pinned_bytes = self.queued_bytes;
+
+        self.counters.dropped(skipped);
+        if skipped > 0 || unreported > 0 {
+            let dropped = skipped.saturating_add(unreported);
+            self.enqueue(gap::dropped(dropped), 0, dropped, false);
+        }
+        skipped
+    }
+
+    /// Reads the requests the client has sent since the last call.
+    ///
+    /// An error means the client is gone and should be dropped.
+    pub(crate) fn receive(&mut self) -> io::Result<Vec<Request>> {
+        let mut requests = Vec::new();
+        let mut buf = [0; 64];
+        loop {
+            let read = self.transport.read(&mut buf)?;
+            if read == 0 {
+                return Ok(requests);
+            }
+
+            for &byte in &buf[..read] {
+                if byte == b'\n' {
+                    requests.extend(Request::parse(&self.inbound));
+                    self.inbound.clear();
+                } else if self.inbound.len() < MAX_REQUEST_BYTES {
+                    self.inbound.push(byte);
+                }
+            }
+        }
+    }
+
+    /// Writes as much of the queue as the transport accepts without blocking.
+    ///
+    /// An error means the client is gone and should be dropped.
+    pub(crate) fn send(&mut self) -> io::Result<()> {
+        let had_queued = !self.queue.is_empty();
+        while let Some(Queued {
+            encoded: front,
+            pinned,
+            ..
+        }) = self.queue.front()
+        {
+            match self.transport.write(&front[self.offset..])? {
+                0 => return Ok(()),
+                written => {
+                    self.counters.sent(written);
+                    self.offset += written;
+                    self.progress = Instant::now();
+                    if self.offset == front.len() {
+                        self.queued_bytes -= front.len();
+                        if *pinned {
+                            self.pinned_bytes -= front.len();
+                        }
+                        self.offset = 0;
+                        self.queue.pop_front();
+                    }
+                }
+            }
+        }
+
+        if had_queued {
+            self.transport.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// A frame separator, queued when the bytes sent so far may not end on a
+/// frame boundary.
+fn separator() -> Queued {
+    Queued {
+        encoded: vec![0],
+        frames: 0,
+        dropped: 0,
+        pinned: true,
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if let Some(server) = &self.server {
+            server.disconnected();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+
+    /// Writes at most `budget` bytes, then nothing until it is raised.
+    struct Capture {
+        sent: Arc<Mutex<Vec<u8>>>,
+        budget: Arc<AtomicUsize>,
+    }
+
+    impl Transport for Capture {
+        type Error = io::Error;
+
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            let written = bytes.len().min(self.budget.load(Ordering::Relaxed));
+            self.budget.fetch_sub(written, Ordering::Relaxed);
+            self.sent
+                .lock()
+                .unwrap()
+                .extend_from_slice(&bytes[..written]);
+            Ok(written)
+        }
+    }
+
+    fn client() -> (Client, Arc<Mutex<Vec<u8>>>, Arc<AtomicUsize>) {
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        let budget = Arc::new(AtomicUsize::new(0));
+        let transport = Capture {
+            sent: sent.clone(),
+            budget: budget.clone(),
+        };
+        (Client::new(Box::new(transport), None), sent, budget)
+    }
+
+    /// Returns `true` if `bytes` is a single frame with a leading separator,
+    /// such as a drop report. Reports are timestamped, so their length
+    /// varies.
+    fn is_one_frame(bytes: &[u8]) -> bool {
+        bytes.len() > 2
+            && bytes.starts_with(&[0])
+            && bytes.ends_with(&[0])
+            && bytes.iter().filter(|&&byte| byte == 0).count() == 2
+    }
+
+    #[test]
+    fn history_and_control_frames_are_not_skipped() {
+        let (mut client, sent, budget) = client();
+        client.push_control(b"\0hello\0");
+        client.push_history(b"old\0");
+        client.push(b"live\0");
+        assert_eq!(client.queued_bytes(), 17);
+        assert_eq!(client.backlog(), 5);
+
+        assert_eq!(client.skip_queued(), 1);
+        assert_eq!(client.backlog(), client.queued_bytes() - 12);
+
+        budget.store(usize::MAX, Ordering::Relaxed);
+        client.send().unwrap();
+        let sent = sent.lock().unwrap();
+        assert!(sent.starts_with(b"\0\0hello\0old\0"));
+        assert!(is_one_frame(&sent[12..]));
+    }
+
+    #[test]
+    fn skipping_a_partly_written_frame_ends_it() {
+        let (mut client, sent, budget) = client();
+        client.push_control(b"\0hello\0");
+        client.push(b"live\0");
+        budget.store(10, Ordering::Relaxed);
+        client.send().unwrap();
+        assert_eq!(client.backlog(), 3);
+
+        assert_eq!(client.skip_queued(), 1);
+        budget.store(usize::MAX, Ordering::Relaxed);
+        client.send().unwrap();
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent[..11], b"\0\0hello\0li\0"[..]);
+        assert!(is_one_frame(&sent[11..]));
+    }
+}
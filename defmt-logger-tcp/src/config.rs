@@ -0,0 +1,494 @@
+//! Server configuration.
+
+use crate::{
+    error::Error,
+    filter::Filter,
+    ring::{OverflowPolicy, DEFAULT_CAPACITY},
+};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{
+    env, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    time::Duration,
+};
+
+/// The port used when none is configured.
+pub const DEFAULT_PORT: u16 = 19021;
+
+/// The host used when none is configured.
+pub const DEFAULT_HOST: &str = "localhost";
+
+/// The default limit on the size of the history replayed to new clients.
+pub const DEFAULT_HISTORY_BYTES: usize = 64 * 1024;
+
+/// The default limit on the number of frames replayed to new clients.
+pub const DEFAULT_HISTORY_FRAMES: usize = 1024;
+
+/// The default limit on the number of clients connected to a server at once.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 32;
+
+/// How long a connection is idle by default before TCP keepalive probes
+/// check that the client is still there.
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(60);
+
+/// Environment variable that overrides the configured bind addresses.
+///
+/// It holds a comma separated list of addresses, each either a bare host
+/// (`0.0.0.0`, `localhost`) which uses the configured port, or a host and
+/// port (`127.0.0.1:4000`, `[::1]:0`).
+pub const ADDR_ENV: &str = "DEFMT_TCP_ADDR";
+
+/// Environment variable that sets the path of a Unix domain socket to listen
+/// on, replacing any configured path.
+#[cfg(unix)]
+pub const UNIX_PATH_ENV: &str = "DEFMT_TCP_UNIX_PATH";
+
+/// What happens to a client that doesn't read its frames as fast as they are
+/// logged, once the frames queued for it reach the
+/// [client queue limit](ServerConfigBuilder::client_queue_bytes).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SlowClientPolicy {
+    /// Discards the frames queued for the client, and sends it a frame
+    /// saying how many were dropped.
+    ///
+    /// Under [`OverflowPolicy::Block`] logging waits for the client instead,
+    /// until the write timeout disconnects it.
+    #[default]
+    SkipFrames,
+    /// Disconnects the client, so it never holds up logging or sees gaps.
+    Disconnect,
+}
+
+/// Configuration for the TCP log server.
+///
+/// ```rust
+/// use defmt_logger_tcp::ServerConfig;
+///
+/// let config = ServerConfig::builder()
+///     .host("0.0.0.0")
+///     .port(0)
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub(crate) tcp: bool,
+    pub(crate) hosts: Vec<String>,
+    pub(crate) port: u16,
+    #[cfg(unix)]
+    pub(crate) unix_path: Option<PathBuf>,
+    #[cfg(unix)]
+    pub(crate) unix_mode: Option<u32>,
+    pub(crate) dual_stack: bool,
+    pub(crate) env_overrides: bool,
+    pub(crate) write_timeout: Duration,
+    pub(crate) buffer_capacity: usize,
+    pub(crate) overflow_policy: OverflowPolicy,
+    pub(crate) history_bytes: usize,
+    pub(crate) history_frames: usize,
+    pub(crate) handshake: bool,
+    pub(crate) serve_table: bool,
+    pub(crate) filter: Filter,
+    pub(crate) max_connections: usize,
+    pub(crate) client_queue_bytes: Option<usize>,
+    pub(crate) slow_client_policy: SlowClientPolicy,
+    pub(crate) keepalive: Option<Duration>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            tcp: true,
+            hosts: vec![DEFAULT_HOST.to_string()],
+            port: DEFAULT_PORT,
+            #[cfg(unix)]
+            unix_path: None,
+            #[cfg(unix)]
+            unix_mode: None,
+            dual_stack: false,
+            env_overrides: true,
+            write_timeout: Duration::from_millis(100),
+            buffer_capacity: DEFAULT_CAPACITY,
+            overflow_policy: OverflowPolicy::default(),
+            history_bytes: DEFAULT_HISTORY_BYTES,
+            history_frames: DEFAULT_HISTORY_FRAMES,
+            handshake: true,
+            serve_table: false,
+            filter: Filter::default(),
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            client_queue_bytes: None,
+            slow_client_policy: SlowClientPolicy::default(),
+            keepalive: Some(DEFAULT_KEEPALIVE),
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Returns a builder starting from the default configuration.
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder {
+            config: Self::default(),
+            hosts_set: false,
+        }
+    }
+
+    /// Resolves the configured hosts into the socket addresses to bind, as
+    /// one list per listener of the addresses to try in turn.
+    pub(crate) fn resolve(&self) -> Result<Vec<Vec<SocketAddr>>, Error> {
+        if !self.tcp {
+            return Ok(Vec::new());
+        }
+
+        let hosts = match env::var(ADDR_ENV) {
+            Ok(value) if self.env_overrides => value
+                .split(',')
+                .map(str::trim)
+                .filter(|host| !host.is_empty())
+                .map(str::to_string)
+                .collect(),
+            _ => self.hosts.clone(),
+        };
+
+        let mut addrs = Vec::new();
+        for host in &hosts {
+            let mut resolved = resolve_host(host, self.port).map_err(|source| Error::Resolve {
+                host: host.clone(),
+                source,
+            })?;
+            let listeners = if self.dual_stack {
+                pair_unspecified(&mut resolved);
+                resolved.into_iter().map(|addr| vec![addr]).collect()
+            } else {
+                // Behave like `TcpListener::bind`, which listens on the first
+                // address that binds successfully.
+                vec![resolved]
+            };
+
+            for alternatives in listeners {
+                if !alternatives.is_empty() && !addrs.contains(&alternatives) {
+                    addrs.push(alternatives);
+                }
+            }
+        }
+
+        if addrs.is_empty() {
+            return Err(Error::Resolve {
+                host: hosts.join(","),
+                source: io::Error::new(io::ErrorKind::InvalidInput, "no addresses to bind"),
+            });
+        }
+
+        Ok(addrs)
+    }
+
+    /// Returns the path of the Unix domain socket to listen on, if any.
+    #[cfg(unix)]
+    pub(crate) fn unix_path(&self) -> Option<PathBuf> {
+        match env::var_os(UNIX_PATH_ENV) {
+            Some(path) if self.env_overrides && !path.is_empty() => Some(path.into()),
+            _ => self.unix_path.clone(),
+        }
+    }
+}
+
+/// Builder for [`ServerConfig`].
+#[derive(Debug, Clone)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+    hosts_set: bool,
+}
+
+impl ServerConfigBuilder {
+    /// Adds a host to bind, either a bare host or a `host:port` pair.
+    ///
+    /// The first call replaces the default of `localhost`.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        if !self.hosts_set {
+            self.config.hosts.clear();
+            self.hosts_set = true;
+        }
+        self.config.hosts.push(host.into());
+        self
+    }
+
+    /// Sets the port used for hosts without an explicit port.
+    ///
+    /// Port `0` asks the operating system for a free port, use
+    /// [`Server::local_addrs`](crate::Server::local_addrs) to discover it.
+    pub fn port(mut self, port: u16) -> Self {
+        self.config.port = port;
+        self
+    }
+
+    /// Binds every address a host resolves to, instead of only the first.
+    ///
+    /// Unspecified addresses (`0.0.0.0` or `::`) are also paired with their
+    /// counterpart in the other address family, so both IPv4 and IPv6
+    /// clients can connect.
+    pub fn dual_stack(mut self, dual_stack: bool) -> Self {
+        self.config.dual_stack = dual_stack;
+        self
+    }
+
+    /// Whether to listen on TCP at all. Enabled by default, disable it to
+    /// only listen on a Unix domain socket.
+    pub fn tcp(mut self, tcp: bool) -> Self {
+        self.config.tcp = tcp;
+        self
+    }
+
+    /// Also listens on a Unix domain socket at `path`.
+    ///
+    /// A socket already at `path` is replaced if nothing is listening on it,
+    /// eg. because the process that created it exited without removing it.
+    /// The socket is removed again when the server is dropped.
+    #[cfg(unix)]
+    pub fn unix_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.unix_path = Some(path.into());
+        self
+    }
+
+    /// Sets the permissions of the Unix domain socket, eg. `0o600` to only
+    /// allow the current user to connect. By default the process umask
+    /// applies.
+    #[cfg(unix)]
+    pub fn unix_permissions(mut self, mode: u32) -> Self {
+        self.config.unix_mode = Some(mode);
+        self
+    }
+
+    /// Whether the [`DEFMT_TCP_ADDR`](ADDR_ENV) and `DEFMT_TCP_UNIX_PATH`
+    /// environment variables may override the configured hosts and Unix
+    /// socket path. Enabled by default.
+    pub fn env_overrides(mut self, env_overrides: bool) -> Self {
+        self.config.env_overrides = env_overrides;
+        self
+    }
+
+    /// Sets how long a client may go without accepting any of the frames
+    /// queued for it before it is disconnected.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.config.write_timeout = timeout;
+        self
+    }
+
+    /// Sets the size in bytes of the buffer frames wait in until the writer
+    /// thread sends them.
+    ///
+    /// This only takes effect for the first server started in the process,
+    /// and not at all if a transport was [added](crate::add_transport)
+    /// before it, as the buffer is set up then.
+    pub fn buffer_capacity(mut self, capacity: usize) -> Self {
+        self.config.buffer_capacity = capacity;
+        self
+    }
+
+    /// Sets what happens to frames logged while the buffer is full.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.config.overflow_policy = policy;
+        self
+    }
+
+    /// Limits the total size of the recent frames replayed to each client
+    /// when it connects, so it sees what was logged before it attached.
+    ///
+    /// Set to `0` to disable the history.
+    pub fn history_bytes(mut self, bytes: usize) -> Self {
+        self.config.history_bytes = bytes;
+        self
+    }
+
+    /// Limits the number of recent frames replayed to each client when it
+    /// connects.
+    ///
+    /// Set to `0` to disable the history.
+    pub fn history_frames(mut self, frames: usize) -> Self {
+        self.config.history_frames = frames;
+        self
+    }
+
+    /// Whether to send each client a handshake before any frames, with the
+    /// protocol version, process id, session start time and the executable's
+    /// [build-id](crate::elf_id).
+    ///
+    /// Clients can use it to tell when the process has restarted, and to
+    /// check they are decoding with the right ELF. The handshake is a
+    /// [control frame](crate::CONTROL_INDEX), which `defmt-print` skips.
+    /// Enabled by default.
+    pub fn handshake(mut self, handshake: bool) -> Self {
+        self.config.handshake = handshake;
+        self
+    }
+
+    /// Whether to send the executable's defmt table to clients that ask for
+    /// it, so they can decode frames without a copy of the ELF.
+    ///
+    /// A client asks by sending the line `table\n`, and is sent the table in
+    /// [`CONTROL_TABLE`](crate::CONTROL_TABLE) frames, interleaved with its
+    /// log frames. When this is disabled, or the executable can't be read,
+    /// it is sent a single table frame saying there is no table. Disabled by
+    /// default, as the table holds every format string in the program.
+    pub fn serve_table(mut self, serve_table: bool) -> Self {
+        self.config.serve_table = serve_table;
+        self
+    }
+
+    /// Sets the filter each client starts with, until it sends its own.
+    ///
+    /// Defaults to sending every frame.
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.config.filter = filter;
+        self
+    }
+
+    /// Limits the number of clients connected at once. Connections beyond
+    /// the limit are closed straight away, so a burst of clients can't fill
+    /// memory with their queues, or hold up sending to the others.
+    ///
+    /// Defaults to [`DEFAULT_MAX_CONNECTIONS`].
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.config.max_connections = max;
+        self
+    }
+
+    /// Limits the bytes queued for each client, beyond which the
+    /// [`SlowClientPolicy`] applies.
+    ///
+    /// Defaults to the [buffer capacity](Self::buffer_capacity).
+    pub fn client_queue_bytes(mut self, bytes: usize) -> Self {
+        self.config.client_queue_bytes = Some(bytes);
+        self
+    }
+
+    /// Sets what happens to clients that fall behind. Defaults to skipping
+    /// frames.
+    pub fn slow_client_policy(mut self, policy: SlowClientPolicy) -> Self {
+        self.config.slow_client_policy = policy;
+        self
+    }
+
+    /// Sets how long a TCP connection may be idle before keepalive probes
+    /// are sent, so clients that went away without closing the connection,
+    /// eg. because their machine lost power, are disconnected. `None`
+    /// disables keepalive.
+    ///
+    /// Defaults to [`DEFAULT_KEEPALIVE`].
+    pub fn keepalive(mut self, idle: Option<Duration>) -> Self {
+        self.config.keepalive = idle;
+        self
+    }
+
+    /// Builds the configuration.
+    pub fn build(self) -> ServerConfig {
+        self.config
+    }
+}
+
+fn resolve_host(host: &str, port: u16) -> io::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = host.parse::<SocketAddr>() {
+        return Ok(vec![addr]);
+    }
+
+    if let Ok(ip) = host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        return Ok(vec![SocketAddr::new(ip, port)]);
+    }
+
+    let addrs = match host.rsplit_once(':') {
+        Some((name, explicit)) if !name.contains(':') => {
+            let explicit = explicit.parse::<u16>().map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid port in {host}"),
+                )
+            })?;
+            (name, explicit).to_socket_addrs()?
+        }
+        _ => (host, port).to_socket_addrs()?,
+    };
+
+    Ok(addrs.collect())
+}
+
+fn pair_unspecified(addrs: &mut Vec<SocketAddr>) {
+    let counterparts: Vec<SocketAddr> = addrs
+        .iter()
+        .filter(|addr| addr.ip().is_unspecified())
+        .map(|addr| match addr.ip() {
+            IpAddr::V4(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), addr.port()),
+            IpAddr::V6(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), addr.port()),
+        })
+        .collect();
+
+    for addr in counterparts {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_addresses_and_hosts() {
+        let v4: SocketAddr = "192.0.2.1:4000".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:4000".parse().unwrap();
+
+        assert_eq!(resolve_host("192.0.2.1:4000", 19021).unwrap(), [v4]);
+        assert_eq!(resolve_host("192.0.2.1", 4000).unwrap(), [v4]);
+        assert_eq!(resolve_host("[2001:db8::1]:4000", 19021).unwrap(), [v6]);
+        assert_eq!(resolve_host("[2001:db8::1]", 4000).unwrap(), [v6]);
+        assert_eq!(resolve_host("2001:db8::1", 4000).unwrap(), [v6]);
+
+        let localhost = resolve_host("localhost:4000", 19021).unwrap();
+        assert!(!localhost.is_empty());
+        assert!(localhost
+            .iter()
+            .all(|addr| addr.ip().is_loopback() && addr.port() == 4000));
+    }
+
+    #[test]
+    fn keeps_every_address_to_fall_back_on() {
+        let config = ServerConfig::builder()
+            .env_overrides(false)
+            .host("localhost:4000")
+            .host("192.0.2.1:4000")
+            .host("localhost:4000")
+            .build();
+        let addrs = config.resolve().unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0], resolve_host("localhost:4000", 19021).unwrap());
+        assert_eq!(addrs[1], ["192.0.2.1:4000".parse().unwrap()]);
+
+        let config = ServerConfig::builder()
+            .env_overrides(false)
+            .host("0.0.0.0:4000")
+            .dual_stack(true)
+            .build();
+        assert_eq!(
+            config.resolve().unwrap(),
+            [
+                vec!["0.0.0.0:4000".parse().unwrap()],
+                vec!["[::]:4000".parse().unwrap()]
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_ports() {
+        let e = resolve_host("localhost:http", 19021).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+        assert!(resolve_host("localhost:70000", 19021).is_err());
+    }
+
+    #[test]
+    fn pairs_unspecified_addresses() {
+        let mut addrs = vec!["0.0.0.0:4000".parse().unwrap()];
+        pair_unspecified(&mut addrs);
+        assert_eq!(addrs[1], "[::]:4000".parse().unwrap());
+
+        pair_unspecified(&mut addrs);
+        assert_eq!(addrs.len(), 2);
+    }
+}
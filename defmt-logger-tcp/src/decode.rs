@@ -0,0 +1,109 @@
+//! A transport that decodes frames in-process and prints them.
+
+use crate::transport::Transport;
+use defmt_decoder::{DecodeError, StreamDecoder, Table};
+use std::{
+    env, fs,
+    io::{self, IsTerminal, Stderr, Stdout, Write},
+    sync::OnceLock,
+};
+
+/// The executable's defmt table, loaded once and shared by every sink.
+static TABLE: OnceLock<Table> = OnceLock::new();
+
+/// Decodes frames with the executable's own `.defmt` table and prints them
+/// as text, so logs can be read without running `defmt-print`.
+///
+/// Lines are formatted like `defmt-print`'s default output, with the
+/// timestamp and level.
+///
+/// ```rust,no_run
+/// use defmt_logger_tcp::DecodeSink;
+/// use std::thread;
+///
+/// defmt_logger_tcp::add_transport(DecodeSink::stderr()?);
+/// thread::spawn(defmt_logger_tcp::run);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct DecodeSink<W> {
+    writer: W,
+    decoder: Box<dyn StreamDecoder + Send + Sync>,
+    colored: bool,
+}
+
+impl DecodeSink<Stderr> {
+    /// Prints to stderr, in colour if it is a terminal.
+    pub fn stderr() -> io::Result<Self> {
+        let stderr = io::stderr();
+        let colored = stderr.is_terminal();
+        Ok(Self::new(stderr)?.colored(colored))
+    }
+}
+
+impl DecodeSink<Stdout> {
+    /// Prints to stdout, in colour if it is a terminal.
+    pub fn stdout() -> io::Result<Self> {
+        let stdout = io::stdout();
+        let colored = stdout.is_terminal();
+        Ok(Self::new(stdout)?.colored(colored))
+    }
+}
+
+impl<W: Write> DecodeSink<W> {
+    /// Prints to `writer`, without colour.
+    ///
+    /// Fails if the executable can't be read or has no defmt table, eg.
+    /// because it isn't an ELF file.
+    pub fn new(writer: W) -> io::Result<Self> {
+        let table = match TABLE.get() {
+            Some(table) => table,
+            None => {
+                let table = load_table()?;
+                TABLE.get_or_init(|| table)
+            }
+        };
+
+        Ok(Self {
+            writer,
+            decoder: table.new_stream_decoder(),
+            colored: false,
+        })
+    }
+
+    /// Whether to colour the log levels.
+    pub fn colored(mut self, colored: bool) -> Self {
+        self.colored = colored;
+        self
+    }
+}
+
+impl<W: Write> Transport for DecodeSink<W> {
+    type Error = io::Error;
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        self.decoder.received(bytes);
+        loop {
+            match self.decoder.decode() {
+                Ok(frame) => writeln!(self.writer, "{}", frame.display(self.colored))?,
+                Err(DecodeError::UnexpectedEof) => break,
+                // The decoder skips to the next frame.
+                Err(DecodeError::Malformed) => {}
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+fn load_table() -> io::Result<Table> {
+    // On Linux this reads `/proc/self/exe`.
+    let elf = fs::read(env::current_exe()?)?;
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+    Table::parse(&elf)
+        .map_err(|e| invalid(format!("failed to parse the defmt table: {e}")))?
+        .ok_or_else(|| invalid("the executable has no defmt table".to_string()))
+}
@@ -0,0 +1,143 @@
+//! The errors the server returns, and the hook that observes faults while
+//! it runs.
+
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{
+    error, fmt, io,
+    net::SocketAddr,
+    sync::{PoisonError, RwLock},
+};
+
+type Hook = Box<dyn Fn(&Error) + Send + Sync>;
+
+static HOOK: RwLock<Option<Hook>> = RwLock::new(None);
+
+/// An error starting the server, or a fault while it runs.
+///
+/// Faults the server recovers from, such as a failed accept or a client that
+/// had to be disconnected, don't stop it. They are passed to the hook set
+/// with [`set_error_hook`] instead.
+///
+/// Converts into an [`io::Error`], so `?` works in functions returning
+/// `io::Result`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// A configured host couldn't be resolved to addresses to listen on.
+    Resolve { host: String, source: io::Error },
+    /// Neither TCP nor a Unix domain socket is configured.
+    NothingToListenOn,
+    /// A TCP address couldn't be bound.
+    Bind { addr: SocketAddr, source: io::Error },
+    /// The Unix domain socket couldn't be bound.
+    #[cfg(unix)]
+    BindUnix { path: PathBuf, source: io::Error },
+    /// A thread couldn't be spawned.
+    Spawn(io::Error),
+    /// Accepting a connection failed, eg. because the process ran out of
+    /// file descriptors. The server retries with a backoff.
+    Accept(io::Error),
+    /// An accepted connection couldn't be set up, and was closed.
+    Connection(io::Error),
+    /// A connection was closed straight away, because the most clients the
+    /// server allows were already connected.
+    TooManyConnections,
+    /// A client was disconnected because writing to it failed, which is
+    /// also how a client going away shows up.
+    Disconnected(io::Error),
+    /// A client was disconnected because it didn't accept any bytes for the
+    /// write timeout.
+    Stalled,
+    /// A client was disconnected because it fell behind, under
+    /// [`SlowClientPolicy::Disconnect`](crate::SlowClientPolicy::Disconnect).
+    FellBehind,
+    /// A transport panicked, and was dropped.
+    TransportPanicked,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Resolve { host, source } => write!(f, "failed to resolve {host}: {source}"),
+            Self::NothingToListenOn => f.write_str("nothing to listen on"),
+            Self::Bind { addr, source } => write!(f, "failed to bind {addr}: {source}"),
+            #[cfg(unix)]
+            Self::BindUnix { path, source } => {
+                write!(f, "failed to bind {}: {source}", path.display())
+            }
+            Self::Spawn(e) => write!(f, "failed to spawn a thread: {e}"),
+            Self::Accept(e) => write!(f, "failed to accept a connection: {e}"),
+            Self::Connection(e) => write!(f, "failed to set up a connection: {e}"),
+            Self::Disconnected(e) => write!(f, "disconnected a client: {e}"),
+            Self::TooManyConnections => f.write_str("refused a connection, too many clients"),
+            Self::Stalled => f.write_str("disconnected a client that stopped reading"),
+            Self::FellBehind => f.write_str("disconnected a client that fell behind"),
+            Self::TransportPanicked => f.write_str("a transport panicked"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Resolve { source, .. } | Self::Bind { source, .. } => Some(source),
+            #[cfg(unix)]
+            Self::BindUnix { source, .. } => Some(source),
+            Self::Spawn(e) | Self::Accept(e) | Self::Connection(e) | Self::Disconnected(e) => {
+                Some(e)
+            }
+            Self::NothingToListenOn
+            | Self::TooManyConnections
+            | Self::Stalled
+            | Self::FellBehind
+            | Self::TransportPanicked => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> Self {
+        let kind = match &error {
+            Error::Resolve { source, .. } | Error::Bind { source, .. } => source.kind(),
+            #[cfg(unix)]
+            Error::BindUnix { source, .. } => source.kind(),
+            Error::Spawn(e) | Error::Accept(e) | Error::Connection(e) | Error::Disconnected(e) => {
+                e.kind()
+            }
+            Error::NothingToListenOn => io::ErrorKind::InvalidInput,
+            Error::TooManyConnections => io::ErrorKind::ConnectionRefused,
+            Error::Stalled => io::ErrorKind::TimedOut,
+            Error::FellBehind | Error::TransportPanicked => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+/// Sets the function called with each fault the server recovers from, eg.
+/// to count them or to log them elsewhere.
+///
+/// The hook is called from the server's threads, including the one that
+/// sends frames, so it should return quickly. For the same reason it must not
+/// log with defmt under [`OverflowPolicy::Block`](crate::OverflowPolicy),
+/// which could wait forever for itself. Without a hook, faults are ignored.
+///
+/// ```rust
+/// use defmt_logger_tcp::Error;
+///
+/// defmt_logger_tcp::set_error_hook(|error| {
+///     if let Error::Accept(e) = error {
+///         eprintln!("defmt-logger-tcp: {e}");
+///     }
+/// });
+/// ```
+pub fn set_error_hook(hook: impl Fn(&Error) + Send + Sync + 'static) {
+    *HOOK.write().unwrap_or_else(PoisonError::into_inner) = Some(Box::new(hook));
+}
+
+/// Passes a fault to the hook, if there is one.
+pub(crate) fn report(error: Error) {
+    if let Some(hook) = &*HOOK.read().unwrap_or_else(PoisonError::into_inner) {
+        hook(&error);
+    }
+}
@@ -0,0 +1,344 @@
+//! A transport that records encoded frames to disk.
+
+use crate::transport::Transport;
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+/// The number of rotated files kept when none is configured.
+pub const DEFAULT_MAX_FILES: usize = 4;
+
+/// Records encoded frames to a file, rotating it by size or age.
+///
+/// The file holds the same stream a TCP client receives, so it can be decoded
+/// later with `defmt-print -e ./target/debug/my-app stdin < my-app.defmt`.
+///
+/// When the file is rotated it is renamed to `<path>.1`, the previous
+/// `<path>.1` to `<path>.2` and so on, and the oldest file beyond the
+/// retention limit is deleted. Files are only rotated between frames, so
+/// each one decodes on its own.
+///
+/// ```rust,no_run
+/// use defmt_logger_tcp::FileSink;
+/// use std::time::Duration;
+///
+/// let sink = FileSink::builder("my-app.defmt")
+///     .max_bytes(16 * 1024 * 1024)
+///     .max_age(Duration::from_secs(60 * 60))
+///     .max_files(8)
+///     .open()?;
+/// defmt_logger_tcp::add_transport(sink);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct FileSink {
+    path: PathBuf,
+    file: BufWriter<File>,
+    /// The size of the current file.
+    written: u64,
+    /// When the current file was opened.
+    opened: Instant,
+    /// Whether the bytes written so far end on a frame boundary.
+    at_boundary: bool,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    max_files: usize,
+}
+
+impl FileSink {
+    /// Opens `path` for appending, without rotation.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        Self::builder(path).open()
+    }
+
+    /// Returns a builder for a sink writing to `path`.
+    pub fn builder(path: impl Into<PathBuf>) -> FileSinkBuilder {
+        FileSinkBuilder {
+            path: path.into(),
+            max_bytes: None,
+            max_age: None,
+            max_files: DEFAULT_MAX_FILES,
+        }
+    }
+
+    fn should_rotate(&self) -> bool {
+        self.written > 0
+            && (self.max_bytes.is_some_and(|max| self.written >= max)
+                || self.max_age.is_some_and(|max| self.opened.elapsed() >= max))
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.max_files == 0 {
+            remove_if_exists(&self.path)?;
+        } else {
+            remove_if_exists(&rotated(&self.path, self.max_files))?;
+            for n in (1..self.max_files).rev() {
+                rename_if_exists(&rotated(&self.path, n), &rotated(&self.path, n + 1))?;
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+
+        self.file = BufWriter::new(File::create(&self.path)?);
+        self.written = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+impl Transport for FileSink {
+    type Error = io::Error;
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        if bytes.is_empty() {
+            return Ok(0);
+        }
+
+        if self.at_boundary && self.should_rotate() {
+            self.rotate()?;
+        }
+
+        // Stop at the end of a frame, so the next write can rotate the file.
+        let len = bytes
+            .iter()
+            .position(|&byte| byte == 0)
+            .map_or(bytes.len(), |end| end + 1);
+        self.file.write_all(&bytes[..len])?;
+        self.written += len as u64;
+        self.at_boundary = bytes[len - 1] == 0;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Builder for [`FileSink`].
+#[derive(Debug, Clone)]
+pub struct FileSinkBuilder {
+    path: PathBuf,
+    max_bytes: Option<u64>,
+    max_age: Option<Duration>,
+    max_files: usize,
+}
+
+impl FileSinkBuilder {
+    /// Rotates the file once it has grown to `bytes`. The file may exceed
+    /// this by up to one frame.
+    pub fn max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Rotates the file once it has been open for `age`.
+    ///
+    /// Files are only rotated when a frame is written, so an idle file may
+    /// stay open for longer.
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Sets how many rotated files are kept, the oldest beyond this are
+    /// deleted. Defaults to [`DEFAULT_MAX_FILES`].
+    pub fn max_files(mut self, files: usize) -> Self {
+        self.max_files = files;
+        self
+    }
+
+    /// Opens the file, appending to it if it already exists.
+    pub fn open(self) -> io::Result<FileSink> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let written = file.metadata()?.len();
+
+        Ok(FileSink {
+            path: self.path,
+            file: BufWriter::new(file),
+            written,
+            opened: Instant::now(),
+            at_boundary: true,
+            max_bytes: self.max_bytes,
+            max_age: self.max_age,
+            max_files: self.max_files,
+        })
+    }
+}
+
+/// Returns `<path>.<n>`.
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut rotated = OsString::from(path);
+    rotated.push(format!(".{n}"));
+    rotated.into()
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+fn rename_if_exists(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process, thread};
+
+    /// A directory of its own for each test, removed when it is dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = env::temp_dir().join(format!("defmt-logger-tcp-{}-{name}", process::id()));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn file(&self) -> PathBuf {
+            self.0.join("log.defmt")
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Writes all of `bytes`, as the writer thread would.
+    fn send(sink: &mut FileSink, mut bytes: &[u8]) {
+        while !bytes.is_empty() {
+            let written = sink.write(bytes).unwrap();
+            bytes = &bytes[written..];
+        }
+        Transport::flush(sink).unwrap();
+    }
+
+    fn read(path: &Path) -> Option<Vec<u8>> {
+        fs::read(path).ok()
+    }
+
+    #[test]
+    fn writes_up_to_the_end_of_a_frame() {
+        let dir = TempDir::new("frame-ends");
+        let mut sink = FileSink::open(dir.file()).unwrap();
+        assert_eq!(sink.write(b"ab\0cd\0").unwrap(), 3);
+        assert_eq!(sink.write(b"cd").unwrap(), 2);
+        assert_eq!(sink.write(b"").unwrap(), 0);
+    }
+
+    #[test]
+    fn rotates_by_size_between_frames() {
+        let dir = TempDir::new("size");
+        let path = dir.file();
+        let mut sink = FileSink::builder(&path).max_bytes(8).open().unwrap();
+
+        send(&mut sink, b"aaaa\0bbbb\0");
+        // The limit is reached partway through this frame, which is finished
+        // before the file is rotated.
+        send(&mut sink, b"cccc\0dd");
+        send(&mut sink, b"dd\0eeee\0");
+
+        assert_eq!(read(&rotated(&path, 2)).unwrap(), b"aaaa\0bbbb\0");
+        assert_eq!(read(&rotated(&path, 1)).unwrap(), b"cccc\0dddd\0");
+        assert_eq!(read(&path).unwrap(), b"eeee\0");
+    }
+
+    #[test]
+    fn rotates_by_age() {
+        let dir = TempDir::new("age");
+        let path = dir.file();
+        let mut sink = FileSink::builder(&path)
+            .max_age(Duration::from_millis(100))
+            .open()
+            .unwrap();
+
+        send(&mut sink, b"aaaa\0");
+        send(&mut sink, b"bbbb\0");
+        thread::sleep(Duration::from_millis(150));
+        send(&mut sink, b"cccc\0");
+
+        assert_eq!(read(&rotated(&path, 1)).unwrap(), b"aaaa\0bbbb\0");
+        assert_eq!(read(&path).unwrap(), b"cccc\0");
+    }
+
+    #[test]
+    fn empty_files_are_not_rotated() {
+        let dir = TempDir::new("empty");
+        let path = dir.file();
+        let mut sink = FileSink::builder(&path)
+            .max_age(Duration::ZERO)
+            .open()
+            .unwrap();
+
+        send(&mut sink, b"aaaa\0");
+        assert_eq!(read(&path).unwrap(), b"aaaa\0");
+        assert!(read(&rotated(&path, 1)).is_none());
+    }
+
+    #[test]
+    fn keeps_max_files() {
+        let dir = TempDir::new("retention");
+        let path = dir.file();
+        let mut sink = FileSink::builder(&path)
+            .max_bytes(1)
+            .max_files(2)
+            .open()
+            .unwrap();
+
+        for frame in [b"1\0", b"2\0", b"3\0", b"4\0", b"5\0"] {
+            send(&mut sink, frame);
+        }
+
+        assert_eq!(read(&path).unwrap(), b"5\0");
+        assert_eq!(read(&rotated(&path, 1)).unwrap(), b"4\0");
+        assert_eq!(read(&rotated(&path, 2)).unwrap(), b"3\0");
+        assert!(read(&rotated(&path, 3)).is_none());
+    }
+
+    #[test]
+    fn keeps_no_files_with_max_files_zero() {
+        let dir = TempDir::new("no-retention");
+        let path = dir.file();
+        let mut sink = FileSink::builder(&path)
+            .max_bytes(1)
+            .max_files(0)
+            .open()
+            .unwrap();
+
+        send(&mut sink, b"1\0");
+        send(&mut sink, b"2\0");
+
+        assert_eq!(read(&path).unwrap(), b"2\0");
+        assert!(read(&rotated(&path, 1)).is_none());
+    }
+
+    #[test]
+    fn counts_an_existing_file_towards_its_size() {
+        let dir = TempDir::new("append");
+        let path = dir.file();
+        fs::write(&path, b"old frame\0").unwrap();
+
+        let mut sink = FileSink::builder(&path).max_bytes(8).open().unwrap();
+        send(&mut sink, b"new\0");
+
+        assert_eq!(read(&rotated(&path, 1)).unwrap(), b"old frame\0");
+        assert_eq!(read(&path).unwrap(), b"new\0");
+    }
+}
@@ -0,0 +1,490 @@
+//! Filtering frames by level and module at runtime, per client or transport.
+//!
+//! defmt frames only carry the index of their format string, so the level
+//! and module of each index are looked up in the executable's own `.defmt`
+//! symbols.
+
+use object::{Object, ObjectSection, ObjectSymbol};
+use std::{
+    collections::HashMap,
+    env, fs, io,
+    str::FromStr,
+    sync::{Once, OnceLock},
+    thread,
+};
+
+/// The level and module of each log frame index.
+static FRAMES: OnceLock<HashMap<u16, FrameInfo>> = OnceLock::new();
+
+/// A log level, ordered from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// The least severe level a filter lets through, if any.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LevelFilter {
+    At(Level),
+    Off,
+}
+
+impl LevelFilter {
+    fn allows(self, level: Level) -> bool {
+        match self {
+            Self::At(min) => level >= min,
+            Self::Off => false,
+        }
+    }
+}
+
+impl FromStr for LevelFilter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        Ok(match s.trim().to_ascii_lowercase().as_str() {
+            "trace" => Self::At(Level::Trace),
+            "debug" => Self::At(Level::Debug),
+            "info" => Self::At(Level::Info),
+            "warn" => Self::At(Level::Warn),
+            "error" => Self::At(Level::Error),
+            "off" => Self::Off,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// What is known about the frames logged from one format string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FrameInfo {
+    /// `None` for `defmt::println!`.
+    level: Option<Level>,
+    /// The module path of the log statement, or just its crate if the
+    /// executable has no location info.
+    module: String,
+}
+
+/// Which frames a client or transport is sent.
+///
+/// A frame is checked against the level set for the longest module path it
+/// is in, or the default level if there is none. Frames from
+/// `defmt::println!` have no level, so only pass or fail on their module.
+/// Modules are matched with their submodules, so `my_app::db` also covers
+/// `my_app::db::pool`.
+///
+/// Levels and modules are looked up in the executable's own `.defmt`
+/// symbols, which are loaded on a background thread when a server with a
+/// filter is bound, or a filter is first set. Frames pass unfiltered until
+/// they have been loaded. Without the `decode` feature only the crate of
+/// each log statement is known, so give crate names such as `my_app`; with
+/// it, module paths are read from the debug info.
+///
+/// Clients set their own filter by sending a line such as
+/// `filter warn,my_app=debug`, in the format [`Filter::from_str`]
+/// parses, and `filter` on its own to be sent everything. Each client starts
+/// with the filter from [`ServerConfigBuilder::filter`](crate::ServerConfigBuilder::filter).
+///
+/// ```rust
+/// use defmt_logger_tcp::{Filter, Level};
+///
+/// // Only errors, and nothing from the noisy driver crate.
+/// let dashboard = Filter::new()
+///     .level(Level::Error)
+///     .deny_module("noisy_driver");
+///
+/// // The same filter, as a client would send it.
+/// assert_eq!(dashboard, "error,-noisy_driver".parse()?);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Filter {
+    default: Option<LevelFilter>,
+    modules: Vec<(String, LevelFilter)>,
+    /// Only frames from these modules pass, unless it is empty.
+    allowed: Vec<String>,
+    /// Frames from these modules never pass.
+    denied: Vec<String>,
+}
+
+impl Filter {
+    /// Returns a filter that lets every frame through.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the least severe level sent from modules without a level of
+    /// their own.
+    pub fn level(mut self, level: Level) -> Self {
+        self.default = Some(LevelFilter::At(level));
+        self
+    }
+
+    /// Sets the least severe level sent from `module` and its submodules.
+    pub fn module_level(mut self, module: impl Into<String>, level: Level) -> Self {
+        self.modules.push((module.into(), LevelFilter::At(level)));
+        self
+    }
+
+    /// Only sends frames from `module` and its submodules, or from the other
+    /// modules allowed.
+    pub fn allow_module(mut self, module: impl Into<String>) -> Self {
+        self.allowed.push(module.into());
+        self
+    }
+
+    /// Never sends frames from `module` and its submodules.
+    pub fn deny_module(mut self, module: impl Into<String>) -> Self {
+        self.denied.push(module.into());
+        self
+    }
+
+    /// Returns `true` if the filter lets everything through.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.default.is_none()
+            && self.modules.is_empty()
+            && self.allowed.is_empty()
+            && self.denied.is_empty()
+    }
+
+    /// Returns `true` if a frame with this raw, unencoded content passes.
+    ///
+    /// Frames whose index isn't in the table always pass, as does every
+    /// frame until the table has been loaded, so the writer thread never
+    /// waits for it.
+    pub(crate) fn allows(&self, raw: &[u8]) -> bool {
+        if self.is_empty() {
+            return true;
+        }
+        let Some(frames) = FRAMES.get() else {
+            return true;
+        };
+        // Frames start with the index of their format string.
+        let Some(info) = raw
+            .first_chunk::<2>()
+            .and_then(|index| frames.get(&u16::from_le_bytes(*index)))
+        else {
+            return true;
+        };
+
+        let in_module = |module: &String| in_module(&info.module, module);
+        if !self.allowed.is_empty() && !self.allowed.iter().any(in_module) {
+            return false;
+        }
+        if self.denied.iter().any(in_module) {
+            return false;
+        }
+
+        let directive = self
+            .modules
+            .iter()
+            .filter(|(module, _)| in_module(module))
+            .max_by_key(|(module, _)| module.len())
+            .map(|&(_, level)| level)
+            .or(self.default);
+        match (directive, info.level) {
+            (None, _) => true,
+            (Some(filter), Some(level)) => filter.allows(level),
+            (Some(filter), None) => filter != LevelFilter::Off,
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = io::Error;
+
+    /// Parses an `env_logger` style spec, such as `warn,my_app::db=debug`.
+    ///
+    /// Each comma separated directive is one of:
+    ///
+    /// * a level, `trace` to `error`, or `off` to send nothing by default
+    /// * `module=level` to set the level for a module, where the level may
+    ///   also be `off`
+    /// * `module` to send every level from a module
+    /// * `+module` to only send frames from the modules given this way
+    /// * `-module` to never send frames from a module
+    fn from_str(spec: &str) -> io::Result<Self> {
+        let mut filter = Filter::default();
+        for directive in spec.split(',').map(str::trim) {
+            if directive.is_empty() {
+                continue;
+            }
+
+            if let Some(module) = directive.strip_prefix('+') {
+                filter.allowed.push(module.trim().to_string());
+            } else if let Some(module) = directive.strip_prefix('-') {
+                filter.denied.push(module.trim().to_string());
+            } else if let Some((module, level)) = directive.split_once('=') {
+                let level = level.parse().map_err(|()| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("invalid level in filter directive `{directive}`"),
+                    )
+                })?;
+                filter.modules.push((module.trim().to_string(), level));
+            } else if let Ok(level) = directive.parse() {
+                filter.default = Some(level);
+            } else {
+                filter
+                    .modules
+                    .push((directive.to_string(), LevelFilter::At(Level::Trace)));
+            }
+        }
+        Ok(filter)
+    }
+}
+
+/// Returns `true` if `module` is `prefix` or one of its submodules.
+fn in_module(module: &str, prefix: &str) -> bool {
+    module
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// Starts loading the level and module of each log frame index from the
+/// executable, if it hasn't been already. Called when a server with a filter
+/// is bound or a filter is set, so it is usually loaded by the time frames
+/// need filtering.
+///
+/// Reading the debug info for module paths can take a while, so it is done
+/// on its own thread rather than holding up the caller or the writer thread.
+pub(crate) fn load_frames() {
+    static LOADING: Once = Once::new();
+
+    LOADING.call_once(|| {
+        thread::spawn(|| {
+            FRAMES.get_or_init(|| {
+                env::current_exe()
+                    .and_then(fs::read)
+                    .map_or_else(|_| HashMap::new(), |elf| read_frames(&elf))
+            });
+        });
+    });
+}
+
+fn read_frames(elf: &[u8]) -> HashMap<u16, FrameInfo> {
+    let Ok(file) = object::File::parse(elf) else {
+        return HashMap::new();
+    };
+    let Some(section) = file.section_by_name(".defmt") else {
+        return HashMap::new();
+    };
+
+    #[cfg(feature = "decode")]
+    let modules = modules(elf);
+
+    let mut frames = HashMap::new();
+    for symbol in file.symbols() {
+        if symbol.section_index() != Some(section.index()) {
+            continue;
+        }
+        let (Ok(name), Ok(index)) = (symbol.name(), u16::try_from(symbol.address())) else {
+            continue;
+        };
+        let level = match json_field(name, "tag").as_deref() {
+            Some("defmt_trace") => Some(Level::Trace),
+            Some("defmt_debug") => Some(Level::Debug),
+            Some("defmt_info") => Some(Level::Info),
+            Some("defmt_warn") => Some(Level::Warn),
+            Some("defmt_error") => Some(Level::Error),
+            Some("defmt_println") => None,
+            // Not a log statement, eg. the format string of a type.
+            _ => continue,
+        };
+
+        #[cfg(feature = "decode")]
+        let module = modules.get(&index).cloned();
+        #[cfg(not(feature = "decode"))]
+        let module = None;
+        let module = module
+            .or_else(|| json_field(name, "crate_name"))
+            .unwrap_or_default();
+
+        frames.insert(index, FrameInfo { level, module });
+    }
+    frames
+}
+
+/// Returns the module path of each index from the debug info, if there is
+/// any.
+#[cfg(feature = "decode")]
+fn modules(elf: &[u8]) -> HashMap<u16, String> {
+    let Ok(Some(table)) = defmt_decoder::Table::parse(elf) else {
+        return HashMap::new();
+    };
+    let Ok(locations) = table.get_locations(elf) else {
+        return HashMap::new();
+    };
+
+    locations
+        .into_iter()
+        .filter_map(|(index, location)| Some((u16::try_from(index).ok()?, location.module)))
+        .collect()
+}
+
+/// Returns a string field of the JSON object a defmt symbol is named with.
+fn json_field(json: &str, key: &str) -> Option<String> {
+    let mut chars = json.strip_prefix('{')?.chars();
+    loop {
+        let name = json_string(&mut chars)?;
+        if chars.next()? != ':' {
+            return None;
+        }
+        let value = json_string(&mut chars)?;
+        if name == key {
+            return Some(value);
+        }
+        if chars.next()? != ',' {
+            return None;
+        }
+    }
+}
+
+/// Parses a JSON string, returning `None` if `chars` doesn't start with one.
+fn json_string(chars: &mut std::str::Chars) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(string),
+            '\\' => match chars.next()? {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                'r' => string.push('\r'),
+                'b' => string.push('\u{8}'),
+                'f' => string.push('\u{c}'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    let code = u32::from_str_radix(&hex, 16).ok()?;
+                    // Surrogate pairs don't matter for the fields used.
+                    string.push(char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER));
+                }
+                escaped => string.push(escaped),
+            },
+            c => string.push(c),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ERROR: u16 = 1;
+    const WARN: u16 = 2;
+    const DB_DEBUG: u16 = 3;
+    const DRIVER_INFO: u16 = 4;
+    const PRINTLN: u16 = 5;
+
+    /// Fills in the table before any filter loads the test binary's own.
+    fn frame(index: u16) -> [u8; 2] {
+        FRAMES.get_or_init(|| {
+            let info = |level, module: &str| FrameInfo {
+                level,
+                module: module.into(),
+            };
+            HashMap::from([
+                (ERROR, info(Some(Level::Error), "my_app")),
+                (WARN, info(Some(Level::Warn), "my_app")),
+                (DB_DEBUG, info(Some(Level::Debug), "my_app::db")),
+                (DRIVER_INFO, info(Some(Level::Info), "my_app::driver")),
+                (PRINTLN, info(None, "my_app")),
+            ])
+        });
+        index.to_le_bytes()
+    }
+
+    fn passes(filter: &Filter) -> Vec<u16> {
+        [ERROR, WARN, DB_DEBUG, DRIVER_INFO, PRINTLN]
+            .into_iter()
+            .filter(|&index| filter.allows(&frame(index)))
+            .collect()
+    }
+
+    #[test]
+    fn parses_specs() {
+        let filter: Filter = " warn , my_app::db=debug,my_app::driver,+my_app,-my_app::x,"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            filter,
+            Filter::new()
+                .level(Level::Warn)
+                .module_level("my_app::db", Level::Debug)
+                .module_level("my_app::driver", Level::Trace)
+                .allow_module("my_app")
+                .deny_module("my_app::x")
+        );
+
+        assert_eq!("".parse::<Filter>().unwrap(), Filter::new());
+        assert!("".parse::<Filter>().unwrap().is_empty());
+        assert_eq!(
+            "OFF".parse::<Filter>().unwrap().default,
+            Some(LevelFilter::Off)
+        );
+        assert!("my_app=loud".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn empty_filter_passes_everything() {
+        assert_eq!(passes(&Filter::new()).len(), 5);
+        // Including frames that aren't in the table.
+        assert!(Filter::new().level(Level::Error).allows(&frame(999)));
+        assert!(Filter::new().level(Level::Error).allows(&[]));
+    }
+
+    #[test]
+    fn filters_by_level() {
+        assert_eq!(
+            passes(&Filter::new().level(Level::Warn)),
+            [ERROR, WARN, PRINTLN]
+        );
+        assert!(passes(&"off".parse().unwrap()).is_empty());
+    }
+
+    #[test]
+    fn longest_module_wins() {
+        let filter = "error,my_app=warn,my_app::db=debug".parse().unwrap();
+        assert_eq!(passes(&filter), [ERROR, WARN, DB_DEBUG, PRINTLN]);
+
+        let filter = "my_app::db=off".parse().unwrap();
+        assert_eq!(passes(&filter), [ERROR, WARN, DRIVER_INFO, PRINTLN]);
+    }
+
+    #[test]
+    fn allows_and_denies_modules() {
+        let filter = Filter::new().allow_module("my_app::db");
+        assert_eq!(passes(&filter), [DB_DEBUG]);
+
+        let filter = Filter::new().deny_module("my_app::driver");
+        assert_eq!(passes(&filter), [ERROR, WARN, DB_DEBUG, PRINTLN]);
+    }
+
+    #[test]
+    fn modules_match_whole_path_segments() {
+        assert!(in_module("my_app", "my_app"));
+        assert!(in_module("my_app::db::pool", "my_app::db"));
+        assert!(!in_module("my_application", "my_app"));
+        assert!(!in_module("my_app", "my_app::db"));
+    }
+
+    #[test]
+    fn reads_json_fields() {
+        let symbol = r#"{"package":"my-app","tag":"defmt_info","data":"a \"quoted\" é\n","disambiguator":"1","crate_name":"my_app"}"#;
+        assert_eq!(json_field(symbol, "tag").as_deref(), Some("defmt_info"));
+        assert_eq!(json_field(symbol, "crate_name").as_deref(), Some("my_app"));
+        assert_eq!(
+            json_field(symbol, "data").as_deref(),
+            Some("a \"quoted\" \u{e9}\n")
+        );
+        assert_eq!(json_field(symbol, "missing"), None);
+        assert_eq!(json_field("_defmt_version_ = 4", "tag"), None);
+        assert_eq!(json_field(r#"{"tag":"unterminated"#, "tag"), None);
+    }
+}
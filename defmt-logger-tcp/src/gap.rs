@@ -0,0 +1,116 @@
+//! The frames that tell readers frames were dropped.
+//!
+//! They are ordinary `defmt::println!` frames, so every decoder shows them,
+//! but they are sent to particular clients rather than logged to all of
+//! them. The logger hands the bytes of frames logged on a thread back here
+//! while one is being made, instead of buffering them.
+//!
+//! Making a frame this way also tells whether `DEFMT_LOG` compiled in a log
+//! statement, without logging anything, which the `log` and `tracing`
+//! bridges check before using a level.
+
+use crate::protocol;
+use std::cell::RefCell;
+
+thread_local! {
+    /// The raw frame being made on this thread, if any.
+    static CAPTURED: RefCell<Option<Vec<u8>>> = const { RefCell::new(None) };
+}
+
+/// Returns the encoded frame saying `frames` frames were dropped.
+pub(crate) fn dropped(frames: u32) -> Vec<u8> {
+    protocol::encode(&make(|| defmt::println!("{=u32} frames dropped", frames)))
+}
+
+/// Returns the raw frame `log` makes, which is empty if it logs nothing.
+///
+/// While the thread is exiting nothing can be captured, so the frame is
+/// logged as usual and the result is empty.
+pub(crate) fn make(log: impl FnOnce()) -> Vec<u8> {
+    let _ = CAPTURED.try_with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    log();
+    CAPTURED
+        .try_with(|captured| captured.borrow_mut().take())
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+}
+
+/// Returns which levels, from `trace` to `error`, `DEFMT_LOG` compiled in
+/// for the module this is used in.
+#[cfg(any(feature = "log", feature = "tracing"))]
+macro_rules! levels_compiled_in {
+    () => {
+        [
+            $crate::gap::make(|| defmt::trace!("")),
+            $crate::gap::make(|| defmt::debug!("")),
+            $crate::gap::make(|| defmt::info!("")),
+            $crate::gap::make(|| defmt::warn!("")),
+            $crate::gap::make(|| defmt::error!("")),
+        ]
+        .map(|raw| !raw.is_empty())
+    };
+}
+#[cfg(any(feature = "log", feature = "tracing"))]
+pub(crate) use levels_compiled_in;
+
+/// Returns `true` if a frame is being made on this thread, so the logger
+/// should leave it alone.
+///
+/// Thread local destructors can log while the thread's locals are torn
+/// down, when nothing is being made.
+pub(crate) fn capturing() -> bool {
+    CAPTURED
+        .try_with(|captured| captured.borrow().is_some())
+        .unwrap_or(false)
+}
+
+/// Adds bytes to the frame being made on this thread, returning `false` if
+/// there is none.
+pub(crate) fn capture(bytes: &[u8]) -> bool {
+    CAPTURED
+        .try_with(|captured| match &mut *captured.borrow_mut() {
+            Some(raw) => {
+                raw.extend_from_slice(bytes);
+                true
+            }
+            None => false,
+        })
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    struct LogsOnDrop;
+
+    impl Drop for LogsOnDrop {
+        fn drop(&mut self) {
+            defmt::println!("dropped");
+        }
+    }
+
+    thread_local! {
+        static LOGS_ON_DROP: LogsOnDrop = const { LogsOnDrop };
+    }
+
+    #[test]
+    fn logging_while_thread_locals_are_torn_down() {
+        thread::spawn(|| {
+            // Destructors run in the reverse order of first use, so the frame
+            // is logged after `CAPTURED` is gone.
+            LOGS_ON_DROP.with(|_| {});
+            assert!(!capturing());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn makes_frames_without_logging_them() {
+        assert!(!make(|| defmt::println!("made")).is_empty());
+        assert!(!capturing());
+    }
+}
@@ -10,40 +10,239 @@
 //!
 //! info!("Hello, world!");
 //! ```
+//!
+//! [`start`] does the same on a background thread, returning a
+//! [`ServerHandle`] to shut the server down with, so the last frames are sent
+//! before the process exits.
+//!
+//! Frames are timestamped with the time since the process started, which
+//! `defmt-print` shows with `{t}` in its log format. Enable the
+//! `timestamp-unix` feature for wall clock time instead, or
+//! `timestamp-callback` to provide your own with `set_timestamp_fn`.
+//!
+//! ## Composing with other defmt crates
+//!
+//! By default this crate defines the `_defmt_timestamp` and `_defmt_panic`
+//! symbols, which only one crate in a binary may do. To provide them from
+//! elsewhere, disable the default features and re-enable the ones you need:
+//!
+//! ```toml
+//! defmt-logger-tcp = { version = "0.2", default-features = false, features = ["std"] }
+//! ```
+//!
+//! * Without a `timestamp-*` feature, use `defmt::timestamp!` in your
+//!   application, or leave frames without a timestamp.
+//! * Without `panic-handler`, use `#[defmt::panic_handler]` in your
+//!   application, or a crate that provides one such as `panic-probe` or
+//!   `defmt-test`. If nothing does, defmt's default handler panics without
+//!   a message.
+//!
+//! The listen address can be changed with [`ServerConfig`], or at runtime
+//! with the `DEFMT_TCP_ADDR` environment variable:
+//!
+//! ```rust,no_run
+//! use defmt_logger_tcp::{Server, ServerConfig};
+//! use std::thread;
+//!
+//! let config = ServerConfig::builder().host("0.0.0.0").port(0).build();
+//! let server = Server::bind(config)?;
+//! let addrs = server.local_addrs()?;
+//!
+//! thread::spawn(move || server.serve());
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! On Unix, logs can also be served on a Unix domain socket with
+//! `ServerConfig::builder().unix_path(..)`, or the `DEFMT_TCP_UNIX_PATH`
+//! environment variable.
+//!
+//! Frames can also be sent to other destinations, such as files, stdout or
+//! in-memory buffers in tests, by passing anything that implements
+//! [`Transport`] to `add_transport`. `FileSink` records them to a file that
+//! is rotated by size or age, so logs are kept when no client is connected.
+//! With the `decode` feature, `DecodeSink` decodes frames in-process and
+//! prints them to stderr, so `defmt-print` isn't needed during development.
+//!
+//! ## Bridging from `log` and `tracing`
+//!
+//! With the `log` feature, `init_log` installs a `log::Log` implementation
+//! that forwards records from the `log` crate as defmt frames, so both show
+//! up in the same stream. Likewise, the `tracing` feature provides
+//! `DefmtLayer`, a `tracing_subscriber::Layer` that logs `tracing` events.
+//! Records and events at levels the `DEFMT_LOG` filter leaves out, which are
+//! all but `error` when it is unset, are logged with `defmt::println!`
+//! instead, with their level in the message.
+//!
+//! ## Without `std`
+//!
+//! With `default-features = false` the crate is `no_std`, and leaves sending
+//! frames to the application. Provide a buffer with `init`, implement
+//! [`Transport`] for your sockets or serial ports, and poll a `Multiplexer`
+//! from your main loop:
+//!
+//! ```rust,ignore
+//! use defmt_logger_tcp::{Link, Multiplexer, OverflowPolicy};
+//!
+//! static mut BUFFER: [u8; 4096] = [0; 4096];
+//!
+//! defmt_logger_tcp::init(unsafe { &mut *core::ptr::addr_of_mut!(BUFFER) }, OverflowPolicy::DropOldest);
+//!
+//! let mut mux = Multiplexer::<256>::new();
+//! let mut links = [Link::new(socket)];
+//! loop {
+//!     mux.poll(&mut links);
+//! }
+//! ```
+//!
+//! The logger takes a `critical-section` lock while writing each frame, so
+//! an implementation must be provided, as with `defmt-rtt`.
+//!
+//! `init` and `Multiplexer` are available with `std` too, to send frames
+//! from an event loop instead of the writer thread. Don't start a server or
+//! add a transport then, as the writer thread takes over the buffer.
+
+#![cfg_attr(not(feature = "std"), no_std)]
 
-use defmt::{Encoder, Formatter};
+#[cfg(feature = "std")]
+mod client;
+#[cfg(feature = "std")]
+mod config;
+#[cfg(feature = "decode")]
+mod decode;
+#[cfg(feature = "std")]
+mod error;
+#[cfg(feature = "std")]
+mod file;
+#[cfg(feature = "std")]
+mod filter;
+#[cfg(feature = "std")]
+mod gap;
+mod lock;
+#[cfg(feature = "log")]
+mod log_bridge;
+mod mux;
+#[cfg(feature = "std")]
+mod protocol;
+mod ring;
+#[cfg(feature = "std")]
+mod server;
+#[cfg(feature = "std")]
+mod stats;
+#[cfg(feature = "std")]
+mod timestamp;
+#[cfg(feature = "tracing")]
+mod tracing_layer;
+mod transport;
+#[cfg(feature = "std")]
+mod writer;
 
+#[cfg(all(feature = "std", unix))]
+pub use config::UNIX_PATH_ENV;
 #[cfg(feature = "std")]
-use std::{
-    io::{self, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Mutex,
-    },
-    time::Duration,
+pub use config::{
+    ServerConfig, ServerConfigBuilder, SlowClientPolicy, ADDR_ENV, DEFAULT_HISTORY_BYTES,
+    DEFAULT_HISTORY_FRAMES, DEFAULT_HOST, DEFAULT_KEEPALIVE, DEFAULT_MAX_CONNECTIONS, DEFAULT_PORT,
 };
+#[cfg(feature = "decode")]
+pub use decode::DecodeSink;
+#[cfg(feature = "std")]
+pub use error::{set_error_hook, Error};
+#[cfg(feature = "std")]
+pub use file::{FileSink, FileSinkBuilder, DEFAULT_MAX_FILES};
+#[cfg(feature = "std")]
+pub use filter::{Filter, Level};
+#[cfg(feature = "log")]
+pub use log_bridge::{init_log, LogBridge};
+pub use mux::{Link, Multiplexer};
+#[cfg(feature = "std")]
+pub use protocol::{elf_id, CONTROL_HANDSHAKE, CONTROL_INDEX, CONTROL_TABLE, PROTOCOL_VERSION};
+pub use ring::OverflowPolicy;
+#[cfg(feature = "std")]
+pub use ring::DEFAULT_CAPACITY;
+#[cfg(feature = "std")]
+pub use server::{Server, ServerHandle};
+#[cfg(feature = "std")]
+pub use stats::{dropped_frames, serve_metrics, stats, ClientStats, Stats};
+#[cfg(feature = "timestamp-callback")]
+pub use timestamp::set_timestamp_fn;
+#[cfg(feature = "tracing")]
+pub use tracing_layer::DefmtLayer;
+pub use transport::Transport;
+#[cfg(feature = "std")]
+pub use writer::{add_filtered_transport, add_transport};
+
+#[cfg(feature = "std")]
+use std::time::Duration;
 
-static TAKEN: AtomicBool = AtomicBool::new(false);
-static PENDING_STREAMS: Mutex<Vec<(TcpStream, Encoder)>> = Mutex::new(Vec::new());
-static STREAMS: Mutex<Vec<(TcpStream, Encoder)>> = Mutex::new(Vec::new());
+use {lock::FrameLock, ring::RingBuffer};
+
+/// How long `flush` waits for the writer thread to send every frame.
+#[cfg(feature = "std")]
+const FLUSH_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub(crate) static LOCK: FrameLock = FrameLock::new();
+pub(crate) static RING: RingBuffer = RingBuffer::new();
+
+#[cfg(feature = "std")]
+std::thread_local! {
+    /// Whether `defmt::flush` was called while this thread held the frame
+    /// lock, so it waits for the frames to be sent once it lets go.
+    static FLUSHING: core::cell::Cell<bool> = const { core::cell::Cell::new(false) };
+}
 
 /// Run initializes the logger, and starts listening for connections on
-/// `localhost:19021`.
-pub fn run() {
-    let listener = TcpListener::bind("localhost:19021").expect("failed to bind to address");
+/// `localhost:19021` (or the addresses in `DEFMT_TCP_ADDR`), and on the Unix
+/// domain socket in `DEFMT_TCP_UNIX_PATH` if it is set.
+///
+/// This only returns if the server can't be started: failed accepts are
+/// retried, and reported to the [error hook](set_error_hook). See [`Server`]
+/// for more control.
+#[cfg(feature = "std")]
+pub fn run() -> Result<(), Error> {
+    Server::bind(ServerConfig::default())?.serve()
+}
 
-    for stream in listener.incoming() {
-        let stream = stream.expect("failed to accept connection");
+/// Like [`run`], but accepts connections on background threads, returning a
+/// handle to shut the server down with.
+///
+/// ```rust,no_run
+/// use std::time::Duration;
+///
+/// let server = defmt_logger_tcp::start()?;
+///
+/// defmt::info!("Hello, world!");
+/// server.shutdown(Duration::from_secs(1));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[cfg(feature = "std")]
+pub fn start() -> Result<ServerHandle, Error> {
+    Server::bind(ServerConfig::default())?.start()
+}
 
-        // Don't block excessively on writes.
-        let timeout = Duration::from_millis(100);
-        stream
-            .set_write_timeout(Some(timeout))
-            .expect("failed to set write timeout");
+/// Provides the buffer frames wait in until a [`Multiplexer`] sends them.
+///
+/// Frames logged before this is called are dropped.
+///
+/// # Panics
+/// With `std`, if a server or transport has already started the writer
+/// thread, which sends frames from a buffer of its own.
+pub fn init(buffer: &'static mut [u8], policy: OverflowPolicy) {
+    // SAFETY: `AtomicU8` has the same in-memory representation as `u8`, and
+    // the buffer is borrowed exclusively for the rest of the program.
+    let storage = unsafe { &*(buffer as *mut [u8] as *const [core::sync::atomic::AtomicU8]) };
 
-        let mut streams = PENDING_STREAMS.lock().unwrap();
-        streams.push((stream, Encoder::new()));
+    LOCK.lock();
+    #[cfg(feature = "std")]
+    if RING.has_consumer() {
+        // SAFETY: the frame lock is held.
+        unsafe { LOCK.unlock() };
+        panic!("defmt_logger_tcp::init called after the writer thread started");
+    }
+    RING.set_policy(policy);
+    // SAFETY: the frame lock is held, and the writer thread isn't running.
+    unsafe {
+        RING.set_storage(storage);
+        LOCK.unlock();
     }
 }
 
@@ -52,80 +251,68 @@ struct Logger;
 
 unsafe impl defmt::Logger for Logger {
     fn acquire() {
-        if TAKEN.load(Ordering::Relaxed) {
-            panic!("defmt logger taken reentrantly");
+        // Frames made by the writer thread for particular clients don't go
+        // through the buffer.
+        #[cfg(feature = "std")]
+        if gap::capturing() {
+            return;
         }
 
-        TAKEN.store(true, Ordering::Relaxed);
-
-        // Move pending streams to active streams.
-        STREAMS
-            .lock()
-            .unwrap()
-            .extend(PENDING_STREAMS.lock().unwrap().drain(..));
+        LOCK.lock();
 
-        on_all_streams(|stream, encoder| {
-            let mut result: io::Result<()> = Ok(());
-            encoder.start_frame(|bytes| write_stream(stream, bytes, &mut result));
-            result
-        });
+        // SAFETY: the frame lock is held.
+        unsafe { RING.begin_frame() };
     }
 
     unsafe fn release() {
-        on_all_streams(|stream, encoder| {
-            let mut result: io::Result<()> = Ok(());
-            encoder.end_frame(|bytes| write_stream(stream, bytes, &mut result));
-            result
-        });
-
-        // Move pending streams to active streams.
-        STREAMS
-            .lock()
-            .unwrap()
-            .extend(PENDING_STREAMS.lock().unwrap().drain(..));
-
-        TAKEN.store(false, Ordering::Relaxed);
-    }
-
-    unsafe fn write(bytes: &[u8]) {
-        on_all_streams(|stream, encoder| {
-            let mut result: io::Result<()> = Ok(());
-            encoder.write(bytes, |bytes| write_stream(stream, bytes, &mut result));
-            result
-        });
-    }
+        #[cfg(feature = "std")]
+        if gap::capturing() {
+            return;
+        }
 
-    unsafe fn flush() {
-        on_all_streams(|stream, _| stream.flush());
-    }
-}
+        // A panic may have abandoned the frame, and let another thread log.
+        #[cfg(feature = "std")]
+        if !LOCK.is_held() {
+            return;
+        }
 
-fn on_all_streams(op: impl Fn(&mut TcpStream, &mut Encoder) -> io::Result<()>) {
-    let mut streams = STREAMS.lock().unwrap();
+        let _committed = RING.commit_frame();
+        #[cfg(feature = "std")]
+        if let Some(bytes @ 1..) = _committed {
+            stats::logged(bytes, RING.used());
+        }
+        LOCK.unlock();
 
-    let mut streams_to_drop: Vec<SocketAddr> = Vec::new();
-    for (stream, encoder) in streams.iter_mut() {
-        if op(stream, encoder).is_err() {
-            streams_to_drop.push(stream.peer_addr().unwrap());
+        #[cfg(feature = "std")]
+        {
+            writer::notify();
+            // Other threads can log while this one waits.
+            if FLUSHING.with(|flushing| flushing.replace(false)) {
+                writer::wait_sent(FLUSH_TIMEOUT);
+            }
         }
     }
 
-    for stream in streams_to_drop {
-        streams.retain(|(s, _)| s.peer_addr().unwrap() != stream);
+    unsafe fn write(bytes: &[u8]) {
+        #[cfg(feature = "std")]
+        if gap::capture(bytes) || !LOCK.is_held() {
+            return;
+        }
+
+        RING.write(bytes);
     }
-}
 
-fn write_stream(stream: &mut TcpStream, bytes: &[u8], result: &mut io::Result<()>) {
-    if let Err(e) = stream.write_all(bytes) {
-        *result = Err(e);
+    unsafe fn flush() {
+        // Without `std` the frames are sent from the same context, so there
+        // is nothing to wait for. With it, waiting is left to `release`, as
+        // `defmt::flush` holds the frame lock in between.
+        #[cfg(feature = "std")]
+        FLUSHING.with(|flushing| flushing.set(true));
     }
-    *result = Ok(());
 }
 
+#[cfg(feature = "panic-handler")]
 #[export_name = "_defmt_panic"]
 fn defmt_panic(info: &core::panic::PanicInfo) -> ! {
     core::panic!("{}", info);
 }
-
-#[export_name = "_defmt_timestamp"]
-fn defmt_timestamp(_f: Formatter<'_>) {}
@@ -0,0 +1,251 @@
+//! The lock that serializes log frames from multiple threads.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(feature = "std")]
+use std::{cell::Cell, hint, panic, ptr, sync::Once, thread};
+
+#[cfg(not(feature = "std"))]
+use core::cell::UnsafeCell;
+
+/// How many times to spin before yielding to the scheduler.
+#[cfg(feature = "std")]
+const SPIN_LIMIT: u32 = 64;
+
+#[cfg(feature = "std")]
+thread_local! {
+    static HELD: Owner = const { Owner(Cell::new(None)) };
+}
+
+/// The lock held by the current thread, which is released if the thread
+/// exits without releasing it, eg. because a `Format` impl panicked.
+///
+/// The frame in progress is abandoned: the next frame starts over after the
+/// last one committed.
+#[cfg(feature = "std")]
+struct Owner(Cell<Option<&'static FrameLock>>);
+
+#[cfg(feature = "std")]
+impl Drop for Owner {
+    fn drop(&mut self) {
+        if let Some(lock) = self.0.take() {
+            lock.locked.store(false, Ordering::Release);
+        }
+    }
+}
+
+/// A lock held between `Logger::acquire` and `Logger::release`.
+///
+/// A `MutexGuard` can't be carried between those calls, so with `std` this
+/// is a plain flag with acquire/release ordering, plus a thread local to tell
+/// a thread waiting on itself apart from one waiting on another thread, and
+/// to release the lock if its owner dies holding it. A panic hook releases it
+/// when a panic abandons a frame, as the panic may be caught and the thread
+/// carry on, eg. in a thread pool.
+///
+/// Without `std` it is a critical section, as in `defmt-rtt`, so interrupts
+/// can't log while a frame is being written.
+pub(crate) struct FrameLock {
+    locked: AtomicBool,
+    #[cfg(not(feature = "std"))]
+    restore: UnsafeCell<critical_section::RestoreState>,
+}
+
+// SAFETY: `restore` is only accessed by the context holding the lock.
+#[cfg(not(feature = "std"))]
+unsafe impl Sync for FrameLock {}
+
+impl FrameLock {
+    pub(crate) const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            #[cfg(not(feature = "std"))]
+            restore: UnsafeCell::new(critical_section::RestoreState::invalid()),
+        }
+    }
+
+    /// Blocks until the lock is taken by the current thread.
+    ///
+    /// Panics if the current thread already holds the lock, as defmt
+    /// requires of a reentrant `acquire`, after releasing it so other threads
+    /// can carry on. A thread that caught a panic from the middle of a frame
+    /// doesn't hold it any more, unless the panic hook has been replaced.
+    #[cfg(feature = "std")]
+    pub(crate) fn lock(&'static self) {
+        install_panic_hook();
+
+        if HELD.try_with(|held| held.0.get().is_some()) == Ok(true) {
+            abandon();
+            panic!("defmt logger taken reentrantly");
+        }
+
+        let mut spins = 0;
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            if spins < SPIN_LIMIT {
+                spins += 1;
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+
+        // While the thread is exiting the lock can't be released for it, but
+        // it won't log again either.
+        let _ = HELD.try_with(|held| held.0.set(Some(self)));
+    }
+
+    /// Takes the lock by entering a critical section.
+    ///
+    /// Panics if the lock is already held, which can only be by the current
+    /// execution context.
+    #[cfg(not(feature = "std"))]
+    pub(crate) fn lock(&self) {
+        // SAFETY: released in `unlock`, which defmt calls exactly once per
+        // `acquire`.
+        let restore = unsafe { critical_section::acquire() };
+
+        if self.locked.swap(true, Ordering::Acquire) {
+            panic!("defmt logger taken reentrantly");
+        }
+
+        // SAFETY: the lock is held, so nothing else accesses `restore`.
+        unsafe { *self.restore.get() = restore };
+    }
+
+    /// Returns `true` if the current thread holds the lock, which it doesn't
+    /// once a panic has abandoned its frame.
+    #[cfg(feature = "std")]
+    pub(crate) fn is_held(&self) -> bool {
+        // A thread whose locals are gone can't tell, but can't panic either.
+        HELD.try_with(|held| held.0.get().is_some_and(|lock| ptr::eq(lock, self)))
+            .unwrap_or(true)
+    }
+
+    /// Releases the lock taken by [`lock`](Self::lock).
+    ///
+    /// # Safety
+    /// Must only be called by the thread holding the lock.
+    pub(crate) unsafe fn unlock(&self) {
+        #[cfg(feature = "std")]
+        let _ = HELD.try_with(|held| held.0.set(None));
+
+        #[cfg(not(feature = "std"))]
+        let restore = *self.restore.get();
+
+        self.locked.store(false, Ordering::Release);
+
+        #[cfg(not(feature = "std"))]
+        critical_section::release(restore);
+    }
+}
+
+/// Releases the lock held by the current thread, if any, abandoning the
+/// frame in progress.
+#[cfg(feature = "std")]
+fn abandon() {
+    if let Ok(Some(lock)) = HELD.try_with(|held| held.0.take()) {
+        lock.locked.store(false, Ordering::Release);
+    }
+}
+
+/// Chains a panic hook that abandons the frame the panicking thread is
+/// making, if any, so other threads don't wait on it.
+#[cfg(feature = "std")]
+fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+
+    // The hook can't be changed while panicking, so it is left for later.
+    if thread::panicking() {
+        return;
+    }
+    INSTALLED.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            abandon();
+            previous(info);
+        }));
+    });
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn released_when_owner_panics() {
+        static LOCK: FrameLock = FrameLock::new();
+
+        let owner = thread::spawn(|| {
+            LOCK.lock();
+            panic!("a Format impl panicked");
+        });
+        assert!(owner.join().is_err());
+
+        LOCK.lock();
+        // SAFETY: this thread holds the lock.
+        unsafe { LOCK.unlock() };
+    }
+
+    #[test]
+    fn released_when_a_panic_is_caught() {
+        static LOCK: FrameLock = FrameLock::new();
+
+        let caught = panic::catch_unwind(|| {
+            LOCK.lock();
+            panic!("a Format impl panicked");
+        });
+        assert!(caught.is_err());
+        assert!(!LOCK.is_held());
+
+        thread::spawn(|| {
+            LOCK.lock();
+            // SAFETY: this thread holds the lock.
+            unsafe { LOCK.unlock() };
+        })
+        .join()
+        .unwrap();
+
+        // The thread that panicked logs as usual too.
+        LOCK.lock();
+        assert!(LOCK.is_held());
+        // SAFETY: this thread holds the lock.
+        unsafe { LOCK.unlock() };
+    }
+
+    #[test]
+    fn logging_carries_on_after_a_caught_panic() {
+        struct Panics;
+
+        impl defmt::Format for Panics {
+            fn format(&self, _: defmt::Formatter) {
+                panic!("a Format impl panicked");
+            }
+        }
+
+        assert!(panic::catch_unwind(|| defmt::println!("{}", Panics)).is_err());
+        thread::spawn(|| defmt::println!("another thread"))
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn reentrant_lock_panics_and_releases() {
+        static LOCK: FrameLock = FrameLock::new();
+
+        LOCK.lock();
+        let reentered = panic::catch_unwind(|| LOCK.lock());
+        assert!(reentered.unwrap_err().downcast_ref() == Some(&"defmt logger taken reentrantly"));
+
+        thread::spawn(|| {
+            LOCK.lock();
+            // SAFETY: this thread holds the lock.
+            unsafe { LOCK.unlock() };
+        })
+        .join()
+        .unwrap();
+    }
+}
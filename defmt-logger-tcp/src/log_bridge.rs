@@ -0,0 +1,74 @@
+//! Forwarding records from the `log` crate as defmt frames.
+
+use crate::gap;
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::sync::OnceLock;
+
+/// A `log::Log` implementation that logs each record as a defmt frame at
+/// the same level, with its target and message.
+///
+/// The records are logged with defmt's levelled macros from this crate,
+/// which the `DEFMT_LOG` filter this crate was compiled with may leave out.
+/// Records at those levels, which are all but `error` when `DEFMT_LOG` is
+/// unset, are logged with `defmt::println!` instead, with the level at the
+/// start of the message. Build with eg. `DEFMT_LOG=trace` or
+/// `DEFMT_LOG=defmt_logger_tcp=trace` to keep every level, and use
+/// [`log::set_max_level`] to filter at runtime.
+///
+/// ```rust
+/// use std::thread;
+///
+/// defmt_logger_tcp::init_log(log::LevelFilter::Info).unwrap();
+/// thread::spawn(defmt_logger_tcp::run);
+///
+/// log::error!("Hello from log!");
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogBridge;
+
+/// Installs [`LogBridge`] as the global `log` logger, and sets the maximum
+/// level of the records it is passed.
+pub fn init_log(level: LevelFilter) -> Result<(), SetLoggerError> {
+    static BRIDGE: LogBridge = LogBridge;
+
+    log::set_logger(&BRIDGE)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+impl Log for LogBridge {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        // Format the message before taking the defmt logger, in case one of
+        // the arguments logs too.
+        let message = record.args().to_string();
+        let target = record.target();
+        let [trace, debug, info, warn, error] = *compiled_in();
+        match record.level() {
+            Level::Error if error => defmt::error!("{=str}: {=str}", target, message),
+            Level::Warn if warn => defmt::warn!("{=str}: {=str}", target, message),
+            Level::Info if info => defmt::info!("{=str}: {=str}", target, message),
+            Level::Debug if debug => defmt::debug!("{=str}: {=str}", target, message),
+            Level::Trace if trace => defmt::trace!("{=str}: {=str}", target, message),
+            level => defmt::println!("{=str} {=str}: {=str}", level.as_str(), target, message),
+        }
+    }
+
+    fn flush(&self) {
+        defmt::flush();
+    }
+}
+
+/// Returns which levels, from `trace` to `error`, the records can be logged
+/// at.
+fn compiled_in() -> &'static [bool; 5] {
+    static COMPILED_IN: OnceLock<[bool; 5]> = OnceLock::new();
+    COMPILED_IN.get_or_init(|| gap::levels_compiled_in!())
+}
@@ -0,0 +1,213 @@
+//! Sending logged frames to transports from the application, without a
+//! writer thread.
+
+use crate::{transport::Transport, RING};
+use defmt::Encoder;
+
+/// A transport, and how far through the current frame it has got.
+pub struct Link<T> {
+    transport: T,
+    state: LinkState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LinkState {
+    /// Waiting for the next frame boundary, the transport may have seen the
+    /// end of a frame from a previous connection, so a frame separator is
+    /// sent first.
+    Joining,
+    /// Sending frame number `frame`, up to `offset`.
+    Sending { frame: u32, offset: usize },
+    /// The transport failed.
+    Closed,
+}
+
+impl<T: Transport> Link<T> {
+    /// Wraps a transport, it starts receiving frames at the next frame
+    /// boundary.
+    pub const fn new(transport: T) -> Self {
+        Self {
+            transport,
+            state: LinkState::Joining,
+        }
+    }
+
+    /// Returns `true` if the transport failed, after which it receives no
+    /// more frames.
+    pub fn is_closed(&self) -> bool {
+        self.state == LinkState::Closed
+    }
+
+    /// Returns the transport.
+    pub fn transport(&mut self) -> &mut T {
+        &mut self.transport
+    }
+
+    /// Unwraps the transport.
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+
+    /// Sends as much of `frame` as the transport accepts, returning `true`
+    /// once all of it has been sent.
+    fn send(&mut self, number: u32, frame: &[u8]) -> bool {
+        let offset = match self.state {
+            LinkState::Closed => return true,
+            LinkState::Sending { frame, offset } if frame == number => offset,
+            // Only start sending at a frame boundary, a link that joined
+            // partway through a frame waits for the next one.
+            LinkState::Sending { .. } | LinkState::Joining => return true,
+        };
+
+        let mut offset = offset;
+        while offset < frame.len() {
+            match self.transport.write(&frame[offset..]) {
+                Ok(0) => {
+                    self.state = LinkState::Sending {
+                        frame: number,
+                        offset,
+                    };
+                    return false;
+                }
+                Ok(written) => offset += written,
+                Err(_) => {
+                    self.state = LinkState::Closed;
+                    return true;
+                }
+            }
+        }
+
+        if self.transport.flush().is_err() {
+            self.state = LinkState::Closed;
+            return true;
+        }
+
+        self.state = LinkState::Sending {
+            frame: number,
+            offset,
+        };
+        true
+    }
+}
+
+/// Sends logged frames to a set of transports, without allocating.
+///
+/// Frames are encoded into a buffer of `N` bytes, larger frames are dropped.
+/// Only one multiplexer should be polled, as each frame is only read once.
+/// With `std`, it sends nothing once a server or transport has started the
+/// writer thread, which sends the frames instead.
+pub struct Multiplexer<const N: usize> {
+    encoder: Encoder,
+    raw: [u8; N],
+    encoded: [u8; N],
+    len: usize,
+    /// The number of the frame in `encoded`, links compare it with their own
+    /// to tell whether they have joined since it was started.
+    frame: u32,
+    /// Whether every link has finished sending the frame in `encoded`.
+    sent: bool,
+}
+
+impl<const N: usize> Multiplexer<N> {
+    /// Creates a multiplexer with no frame in progress.
+    pub const fn new() -> Self {
+        Self {
+            encoder: Encoder::new(),
+            raw: [0; N],
+            encoded: [0; N],
+            len: 0,
+            frame: 0,
+            sent: true,
+        }
+    }
+
+    /// Sends buffered frames to `links` until they are all sent, or a
+    /// transport can't accept any more bytes.
+    pub fn poll<T: Transport>(&mut self, links: &mut [Link<T>]) {
+        #[cfg(feature = "std")]
+        if RING.has_consumer() {
+            return;
+        }
+
+        loop {
+            if !self.sent {
+                let frame = &self.encoded[..self.len];
+                let mut sent = true;
+                for link in links.iter_mut() {
+                    sent &= link.send(self.frame, frame);
+                }
+                if !sent {
+                    return;
+                }
+                self.sent = true;
+            }
+
+            if !self.next_frame() {
+                return;
+            }
+
+            for link in links.iter_mut() {
+                match link.state {
+                    LinkState::Closed => {}
+                    LinkState::Joining => {
+                        // Send the frame separator along with the frame.
+                        link.state = LinkState::Sending {
+                            frame: self.frame,
+                            offset: 0,
+                        };
+                    }
+                    LinkState::Sending { .. } => {
+                        link.state = LinkState::Sending {
+                            frame: self.frame,
+                            offset: 1,
+                        };
+                    }
+                }
+            }
+        }
+    }
+
+    /// Encodes the next buffered frame, with a leading frame separator that
+    /// only joining links are sent.
+    fn next_frame(&mut self) -> bool {
+        loop {
+            let Some(len) = RING.pop_into(&mut self.raw) else {
+                return false;
+            };
+
+            let mut encoded = 1;
+            let mut overflow = false;
+            let mut write = |bytes: &[u8]| {
+                let end = encoded + bytes.len();
+                if end > N {
+                    overflow = true;
+                } else {
+                    self.encoded[encoded..end].copy_from_slice(bytes);
+                    encoded = end;
+                }
+            };
+
+            // The encoder only writes a separator before its first frame,
+            // which links get from `encoded[0]` instead.
+            self.encoder.start_frame(|_| {});
+            self.encoder.write(&self.raw[..len], &mut write);
+            self.encoder.end_frame(&mut write);
+
+            if overflow || N == 0 {
+                continue;
+            }
+
+            self.encoded[0] = 0;
+            self.len = encoded;
+            self.frame = self.frame.wrapping_add(1);
+            self.sent = false;
+            return true;
+        }
+    }
+}
+
+impl<const N: usize> Default for Multiplexer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
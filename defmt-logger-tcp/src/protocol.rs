@@ -0,0 +1,263 @@
+//! Control frames, sent to clients alongside the log frames, and the
+//! requests clients send back.
+
+use crate::filter::Filter;
+use defmt::Encoder;
+use object::{Object, ObjectSection, ObjectSymbol};
+use std::{env, fs, process, str, sync::OnceLock, time::SystemTime};
+
+/// The string index control frames start with in place of a log frame's.
+///
+/// Control frames are encoded like log frames, but no defmt table has an
+/// entry at this index, so decoders such as `defmt-print` skip them. The
+/// index is followed by a byte giving the kind of control frame, then its
+/// fields, little endian.
+pub const CONTROL_INDEX: u16 = 0xFFFF;
+
+/// The version of the control frame format, sent in the handshake.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The kind of the handshake control frame.
+pub const CONTROL_HANDSHAKE: u8 = 0;
+
+/// The kind of the control frames holding the defmt table, sent in response
+/// to a `table` request.
+pub const CONTROL_TABLE: u8 = 1;
+
+/// The most table data sent in one control frame, so the table doesn't hold
+/// up log frames for long.
+const TABLE_CHUNK_BYTES: usize = 4096;
+
+/// When the logger started, in microseconds since the Unix epoch.
+static SESSION_START: OnceLock<u64> = OnceLock::new();
+
+/// Identifies the executable, so a client can check it decodes with the
+/// right ELF.
+static ELF_ID: OnceLock<Vec<u8>> = OnceLock::new();
+
+/// The encoded control frames holding the defmt table.
+static TABLE: OnceLock<Vec<Vec<u8>>> = OnceLock::new();
+
+/// A request from a client, sent as a line of text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Request {
+    /// Send the defmt table.
+    Table,
+    /// Only send the frames that pass a filter from now on, or every frame
+    /// if it is empty.
+    Filter(Filter),
+}
+
+impl Request {
+    /// Parses a line, without its terminator. Unknown and malformed requests
+    /// are ignored, so clients can send requests newer servers understand.
+    pub(crate) fn parse(line: &[u8]) -> Option<Self> {
+        let line = str::from_utf8(line).ok()?.trim();
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        match command {
+            "table" => Some(Self::Table),
+            "filter" => argument.parse().ok().map(Self::Filter),
+            _ => None,
+        }
+    }
+}
+
+/// Records the session start time, if it hasn't been already.
+pub(crate) fn start_session() -> u64 {
+    *SESSION_START.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_micros() as u64)
+    })
+}
+
+/// Returns the encoded handshake frame.
+///
+/// Its fields are the protocol version (`u8`), the process id (`u32`), the
+/// session start time in microseconds since the Unix epoch (`u64`), and the
+/// [ELF id](elf_id) as a length (`u8`) followed by that many bytes.
+pub(crate) fn handshake() -> Vec<u8> {
+    let elf_id = ELF_ID.get_or_init(|| read_exe().map_or_else(Vec::new, |elf| elf_id(&elf)));
+
+    let mut raw = Vec::with_capacity(17 + elf_id.len());
+    raw.extend_from_slice(&CONTROL_INDEX.to_le_bytes());
+    raw.push(CONTROL_HANDSHAKE);
+    raw.push(PROTOCOL_VERSION);
+    raw.extend_from_slice(&process::id().to_le_bytes());
+    raw.extend_from_slice(&start_session().to_le_bytes());
+    raw.push(elf_id.len() as u8);
+    raw.extend_from_slice(elf_id);
+    encode(&raw)
+}
+
+/// Returns the encoded control frames holding the defmt table, or none if it
+/// can't be read.
+///
+/// The table is split into frames whose fields are the index of the frame
+/// (`u32`), the number of frames (`u32`), then a part of the table as a
+/// length (`u32`) followed by that many bytes. The length is needed as
+/// decoders can't tell trailing zeros from padding. An empty response, a
+/// single frame with a count of `0`, means the table isn't available.
+///
+/// Put together, the table is the symbols a decoder needs to rebuild it: the
+/// `.defmt` symbols and the `_defmt_version_` and `_defmt_encoding_` markers.
+/// It starts with the number of symbols (`u32`), and each symbol is a flag
+/// (`u8`) that is `1` if it is in `.defmt`, its address (`u64`), then its
+/// name as a length (`u16`) followed by that many bytes.
+pub(crate) fn table() -> &'static [Vec<u8>] {
+    TABLE.get_or_init(|| {
+        let Some(table) = read_exe().and_then(|elf| read_table(&elf)) else {
+            return Vec::new();
+        };
+
+        let chunks = table.chunks(TABLE_CHUNK_BYTES);
+        let count = chunks.len() as u32;
+        chunks
+            .enumerate()
+            .map(|(index, chunk)| table_frame(index as u32, count, chunk))
+            .collect()
+    })
+}
+
+/// Returns the response to a `table` request when the table isn't served.
+pub(crate) fn table_unavailable() -> Vec<u8> {
+    table_frame(0, 0, &[])
+}
+
+fn table_frame(index: u32, count: u32, chunk: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(15 + chunk.len());
+    raw.extend_from_slice(&CONTROL_INDEX.to_le_bytes());
+    raw.push(CONTROL_TABLE);
+    raw.extend_from_slice(&index.to_le_bytes());
+    raw.extend_from_slice(&count.to_le_bytes());
+    raw.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+    raw.extend_from_slice(chunk);
+    encode(&raw)
+}
+
+fn read_table(elf: &[u8]) -> Option<Vec<u8>> {
+    let file = object::File::parse(elf).ok()?;
+    let section = file.section_by_name(".defmt")?.index();
+
+    let mut count = 0_u32;
+    let mut table = vec![0; 4];
+    for symbol in file.symbols() {
+        let Ok(name) = symbol.name_bytes() else {
+            continue;
+        };
+        let in_defmt = symbol.section_index() == Some(section);
+        let marker = [
+            &b"_defmt_version_"[..],
+            b"\"_defmt_version_",
+            b"_defmt_encoding_",
+        ]
+        .iter()
+        .any(|prefix| name.starts_with(prefix));
+        if !(in_defmt || marker) || name.len() > usize::from(u16::MAX) {
+            continue;
+        }
+
+        count += 1;
+        table.push(u8::from(in_defmt));
+        table.extend_from_slice(&symbol.address().to_le_bytes());
+        table.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        table.extend_from_slice(name);
+    }
+
+    table[..4].copy_from_slice(&count.to_le_bytes());
+    Some(table)
+}
+
+fn read_exe() -> Option<Vec<u8>> {
+    env::current_exe().and_then(fs::read).ok()
+}
+
+/// Encodes a frame on its own, with a leading frame separator.
+pub(crate) fn encode(raw: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    let mut encoder = Encoder::new();
+    encoder.start_frame(|bytes| encoded.extend_from_slice(bytes));
+    encoder.write(raw, |bytes| encoded.extend_from_slice(bytes));
+    encoder.end_frame(|bytes| encoded.extend_from_slice(bytes));
+    encoded
+}
+
+/// Returns the id the handshake advertises for an executable: its GNU
+/// build-id, or a hash of its defmt table if it has none.
+///
+/// Clients compare it with the id of the ELF they decode with, to check it
+/// is the one the process is running. The id is empty if the executable
+/// can't be identified, eg. because it isn't an ELF file.
+///
+/// ```rust,no_run
+/// let elf = std::fs::read("target/debug/my-app")?;
+/// let advertised: &[u8] = &[/* from the handshake */];
+///
+/// if !advertised.is_empty() && advertised != defmt_logger_tcp::elf_id(&elf) {
+///     eprintln!("my-app has been rebuilt since it was started");
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn elf_id(elf: &[u8]) -> Vec<u8> {
+    let Ok(file) = object::File::parse(elf) else {
+        return Vec::new();
+    };
+
+    if let Ok(Some(build_id)) = file.build_id() {
+        return build_id.to_vec();
+    }
+
+    // The table lives in the names of the symbols in `.defmt`.
+    let Some(section) = file.section_by_name(".defmt") else {
+        return Vec::new();
+    };
+    let hash = file
+        .symbols()
+        .filter(|symbol| symbol.section_index() == Some(section.index()))
+        .fold(FNV_OFFSET, |hash, symbol| {
+            let hash = fnv1a(hash, &symbol.address().to_le_bytes());
+            fnv1a(hash, symbol.name_bytes().unwrap_or_default())
+        });
+    hash.to_le_bytes().to_vec()
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Level;
+
+    #[test]
+    fn parses_requests() {
+        assert_eq!(Request::parse(b"table"), Some(Request::Table));
+        assert_eq!(Request::parse(b" table\r"), Some(Request::Table));
+        assert_eq!(
+            Request::parse(b"filter warn,my_app=debug"),
+            Some(Request::Filter(
+                Filter::new()
+                    .level(Level::Warn)
+                    .module_level("my_app", Level::Debug)
+            ))
+        );
+        assert_eq!(
+            Request::parse(b"filter"),
+            Some(Request::Filter(Filter::new()))
+        );
+    }
+
+    #[test]
+    fn ignores_unknown_and_malformed_requests() {
+        assert_eq!(Request::parse(b""), None);
+        assert_eq!(Request::parse(b"tables"), None);
+        assert_eq!(Request::parse(b"subscribe all"), None);
+        assert_eq!(Request::parse(b"filter my_app=loud"), None);
+        assert_eq!(Request::parse(&[0xff, 0xfe]), None);
+    }
+}
@@ -0,0 +1,615 @@
+//! A bounded, lock-free ring buffer of log frames.
+//!
+//! Frames are written by whichever thread holds the frame lock, and read by
+//! the writer thread. Each frame is stored as a little endian `u32` length
+//! followed by the unencoded frame bytes. Positions only ever move forward,
+//! wrapping around at a multiple of the capacity so they can be reduced
+//! modulo the capacity when indexing the storage, and are compared by how
+//! far apart they are.
+
+use core::{
+    cell::UnsafeCell,
+    hint,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering},
+};
+#[cfg(feature = "std")]
+use std::thread;
+
+/// The size of the length prefix stored before each frame.
+const HEADER: usize = 4;
+
+/// The capacity used when the buffer is first written before being configured.
+#[cfg(feature = "std")]
+pub const DEFAULT_CAPACITY: usize = 64 * 1024;
+
+/// What to do with a frame when the buffer is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Discard the frame being written.
+    DropNewest,
+    /// Discard the oldest buffered frames to make room.
+    #[default]
+    DropOldest,
+    /// Wait for the writer thread to make room, which it only does once
+    /// every client has room for more frames.
+    ///
+    /// Frames are dropped as with [`DropNewest`](Self::DropNewest) while no
+    /// server is running, as nothing would ever make room. A
+    /// [`Multiplexer`](crate::Multiplexer) doesn't make room either, so
+    /// without `std` this always behaves that way.
+    Block,
+}
+
+impl OverflowPolicy {
+    const fn from_u8(value: u8) -> Self {
+        match value {
+            0 => Self::DropNewest,
+            1 => Self::DropOldest,
+            _ => Self::Block,
+        }
+    }
+
+    const fn as_u8(self) -> u8 {
+        match self {
+            Self::DropNewest => 0,
+            Self::DropOldest => 1,
+            Self::Block => 2,
+        }
+    }
+}
+
+pub(crate) struct RingBuffer {
+    storage: UnsafeCell<&'static [AtomicU8]>,
+    /// End of the last committed frame.
+    head: AtomicUsize,
+    /// Start of the oldest unread frame.
+    tail: AtomicUsize,
+    /// Where the frame in progress is being written, owned by the producer.
+    write_pos: AtomicUsize,
+    /// Start of the frame in progress, owned by the producer.
+    frame_start: AtomicUsize,
+    /// Whether the frame in progress didn't fit, owned by the producer.
+    discarding: AtomicBool,
+    policy: AtomicU8,
+    consumer: AtomicBool,
+    /// The number of frames dropped since the consumer last took the count.
+    dropped: AtomicUsize,
+}
+
+// SAFETY: the storage is only replaced while no consumer is running and the
+// frame lock is held, otherwise it is only accessed through atomics.
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    pub(crate) const fn new() -> Self {
+        Self {
+            storage: UnsafeCell::new(&[]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            write_pos: AtomicUsize::new(0),
+            frame_start: AtomicUsize::new(0),
+            discarding: AtomicBool::new(false),
+            policy: AtomicU8::new(OverflowPolicy::DropOldest.as_u8()),
+            consumer: AtomicBool::new(false),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    pub(crate) fn set_policy(&self, policy: OverflowPolicy) {
+        self.policy.store(policy.as_u8(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn policy(&self) -> OverflowPolicy {
+        OverflowPolicy::from_u8(self.policy.load(Ordering::Relaxed))
+    }
+
+    fn storage(&self) -> &'static [AtomicU8] {
+        // SAFETY: see the `Sync` impl.
+        unsafe { *self.storage.get() }
+    }
+
+    /// Replaces the storage, discarding any buffered frames.
+    ///
+    /// # Safety
+    /// The frame lock must be held, and no consumer may have been started.
+    pub(crate) unsafe fn set_storage(&self, storage: &'static [AtomicU8]) {
+        *self.storage.get() = storage;
+        self.head.store(0, Ordering::Relaxed);
+        self.tail.store(0, Ordering::Relaxed);
+    }
+
+    /// Changes the capacity, keeping as many of the newest frames as fit.
+    ///
+    /// The previous storage is leaked, which is why this is only done when
+    /// the writer thread starts.
+    ///
+    /// # Safety
+    /// The frame lock must be held, and no consumer may have been started.
+    #[cfg(feature = "std")]
+    pub(crate) unsafe fn resize(&self, capacity: usize) {
+        debug_assert!(!self.consumer.load(Ordering::Relaxed));
+        let capacity = capacity.max(HEADER + 1);
+        if capacity == self.storage().len() {
+            return;
+        }
+
+        let mut frames = Vec::new();
+        let mut frame = Vec::new();
+        while self.pop(&mut frame) {
+            frames.push(core::mem::take(&mut frame));
+        }
+
+        let mut used: usize = frames.iter().map(|frame| HEADER + frame.len()).sum();
+        let mut skip = 0;
+        while used > capacity {
+            used -= HEADER + frames[skip].len();
+            skip += 1;
+        }
+
+        let storage: Box<[AtomicU8]> = (0..capacity).map(|_| AtomicU8::new(0)).collect();
+        self.set_storage(Box::leak(storage));
+
+        for frame in &frames[skip..] {
+            self.begin_frame();
+            self.write(frame);
+            self.commit_frame();
+        }
+    }
+
+    /// Marks the writer thread as running, after which the capacity is fixed.
+    ///
+    /// # Safety
+    /// The frame lock must be held.
+    #[cfg(feature = "std")]
+    pub(crate) unsafe fn start_consumer(&self) {
+        if self.storage().is_empty() {
+            self.resize(DEFAULT_CAPACITY);
+        }
+        self.consumer.store(true, Ordering::Release);
+    }
+
+    /// Returns `true` once the writer thread reads the buffer.
+    #[cfg(feature = "std")]
+    pub(crate) fn has_consumer(&self) -> bool {
+        self.consumer.load(Ordering::Acquire)
+    }
+
+    /// Starts a new frame.
+    ///
+    /// # Safety
+    /// The frame lock must be held.
+    pub(crate) unsafe fn begin_frame(&self) {
+        // Without `std` frames are dropped until storage is provided.
+        #[cfg(feature = "std")]
+        if self.storage().is_empty() {
+            self.resize(DEFAULT_CAPACITY);
+        }
+
+        let start = self.head.load(Ordering::Relaxed);
+        self.frame_start.store(start, Ordering::Relaxed);
+        self.write_pos.store(start, Ordering::Relaxed);
+        self.discarding.store(false, Ordering::Relaxed);
+
+        self.append(&[0; HEADER]);
+    }
+
+    /// Appends bytes to the frame in progress.
+    ///
+    /// # Safety
+    /// The frame lock must be held, and a frame must have been started.
+    pub(crate) unsafe fn write(&self, bytes: &[u8]) {
+        self.append(bytes);
+    }
+
+    /// Makes the frame in progress visible to the consumer.
+    ///
+    /// Returns the length of the frame, `0` if nothing was logged, or `None`
+    /// if the frame was dropped because it didn't fit.
+    ///
+    /// # Safety
+    /// The frame lock must be held, and a frame must have been started.
+    pub(crate) unsafe fn commit_frame(&self) -> Option<usize> {
+        let start = self.frame_start.load(Ordering::Relaxed);
+        if self.discarding.load(Ordering::Relaxed) {
+            self.write_pos.store(start, Ordering::Relaxed);
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+
+        let end = self.write_pos.load(Ordering::Relaxed);
+        let len = self.distance(start, end) - HEADER;
+        if len == 0 {
+            // Nothing was logged, as with `defmt::flush`.
+            self.write_pos.store(start, Ordering::Relaxed);
+            return Some(0);
+        }
+
+        self.store(start, &(len as u32).to_le_bytes());
+        self.head.store(end, Ordering::Release);
+        Some(len)
+    }
+
+    fn append(&self, bytes: &[u8]) {
+        if self.discarding.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let pos = self.write_pos.load(Ordering::Relaxed);
+        let start = self.frame_start.load(Ordering::Relaxed);
+        if bytes.len() > self.storage().len() - self.distance(start, pos) {
+            // The frame could never fit.
+            self.discarding.store(true, Ordering::Relaxed);
+            return;
+        }
+
+        let end = self.advance(pos, bytes.len());
+        if !self.reserve(end) {
+            self.discarding.store(true, Ordering::Relaxed);
+            return;
+        }
+
+        self.store(pos, bytes);
+        self.write_pos.store(end, Ordering::Relaxed);
+    }
+
+    /// Makes room for the frame in progress to extend up to `end`, which
+    /// must be at most the capacity past the start of the frame.
+    fn reserve(&self, end: usize) -> bool {
+        let capacity = self.storage().len();
+        let mut spins = 0u32;
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            if self.distance(tail, end) <= capacity {
+                return true;
+            }
+
+            match self.policy() {
+                OverflowPolicy::DropNewest => return false,
+                OverflowPolicy::Block if !self.consumer.load(Ordering::Acquire) => return false,
+                OverflowPolicy::Block => {
+                    if spins < 64 {
+                        spins += 1;
+                        hint::spin_loop();
+                    } else {
+                        #[cfg(feature = "std")]
+                        thread::yield_now();
+                    }
+                }
+                OverflowPolicy::DropOldest => {
+                    // The oldest committed frame is never the one in progress,
+                    // as that always fits once everything else is dropped.
+                    let len = self.load_len(tail);
+                    let next = self.advance(tail, HEADER + len);
+                    // The consumer may have read it in the meantime, either way
+                    // the tail moves forward.
+                    if self
+                        .tail
+                        .compare_exchange(tail, next, Ordering::AcqRel, Ordering::Acquire)
+                        .is_ok()
+                    {
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Copies the oldest frame into `frame`, returning `false` if there is none.
+    ///
+    /// Must only be called from a single consumer at a time.
+    #[cfg(feature = "std")]
+    pub(crate) fn pop(&self, frame: &mut Vec<u8>) -> bool {
+        self.pop_with(|bytes, _| {
+            frame.clear();
+            frame.extend(bytes);
+        })
+        .is_some()
+    }
+
+    /// Copies the oldest frame into `buf`, returning its length, or `None`
+    /// if there is none. Frames that don't fit in `buf` are dropped.
+    ///
+    /// Must only be called from a single consumer at a time.
+    pub(crate) fn pop_into(&self, buf: &mut [u8]) -> Option<usize> {
+        loop {
+            let copied = self.pop_with(|bytes, len| {
+                if len > buf.len() {
+                    return None;
+                }
+                for (dst, src) in buf.iter_mut().zip(bytes) {
+                    *dst = src;
+                }
+                Some(len)
+            })?;
+
+            if copied.is_some() {
+                return copied;
+            }
+        }
+    }
+
+    /// Removes the oldest frame, passing its bytes and length to `read`.
+    fn pop_with<R>(
+        &self,
+        mut read: impl FnMut(&mut dyn Iterator<Item = u8>, usize) -> R,
+    ) -> Option<R> {
+        let capacity = self.storage().len();
+        loop {
+            let tail = self.tail.load(Ordering::Acquire);
+            let head = self.head.load(Ordering::Acquire);
+            if tail == head {
+                return None;
+            }
+
+            // The producer may drop the frame (and overwrite it) while it is
+            // being read, in which case the tail will have moved and the
+            // compare exchange below fails.
+            let len = self.load_len(tail);
+            if HEADER + len > capacity {
+                continue;
+            }
+
+            let mut bytes = (0..len).map(|i| self.load(tail, HEADER + i));
+            let result = read(&mut bytes, len);
+
+            if self
+                .tail
+                .compare_exchange(
+                    tail,
+                    self.advance(tail, HEADER + len),
+                    Ordering::AcqRel,
+                    Ordering::Acquire,
+                )
+                .is_ok()
+            {
+                return Some(result);
+            }
+        }
+    }
+
+    /// Returns the number of frames dropped since the last call, because
+    /// they didn't fit.
+    #[cfg(feature = "std")]
+    pub(crate) fn take_dropped(&self) -> usize {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+
+    /// Returns the size of the buffer in bytes.
+    #[cfg(feature = "std")]
+    pub(crate) fn capacity(&self) -> usize {
+        self.storage().len()
+    }
+
+    /// Returns the number of bytes buffered, including frame headers.
+    #[cfg(feature = "std")]
+    pub(crate) fn used(&self) -> usize {
+        self.distance(self.tail(), self.head())
+    }
+
+    /// Returns `true` if `pos` is behind `target`, which must be a position
+    /// taken from this buffer.
+    #[cfg(feature = "std")]
+    pub(crate) fn is_behind(&self, pos: usize, target: usize) -> bool {
+        let distance = self.distance(pos, target);
+        distance != 0 && distance <= self.wrap() / 2
+    }
+
+    /// Returns the position after the last committed frame.
+    #[cfg(feature = "std")]
+    pub(crate) fn head(&self) -> usize {
+        self.head.load(Ordering::Acquire)
+    }
+
+    /// Returns the position of the oldest unread frame.
+    #[cfg(feature = "std")]
+    pub(crate) fn tail(&self) -> usize {
+        self.tail.load(Ordering::Acquire)
+    }
+
+    /// Returns the position at which positions wrap around, the largest
+    /// multiple of the capacity a `usize` holds.
+    fn wrap(&self) -> usize {
+        let capacity = self.storage().len().max(1);
+        usize::MAX - usize::MAX % capacity
+    }
+
+    /// Returns the position `len` bytes after `pos`.
+    fn advance(&self, pos: usize, len: usize) -> usize {
+        let left = self.wrap() - pos;
+        if len >= left {
+            len - left
+        } else {
+            pos + len
+        }
+    }
+
+    /// Returns how many bytes `to` is after `from`.
+    fn distance(&self, from: usize, to: usize) -> usize {
+        if to >= from {
+            to - from
+        } else {
+            self.wrap() - from + to
+        }
+    }
+
+    /// Loads the byte `offset` bytes after `pos`, where `offset` is less than
+    /// the capacity.
+    fn load(&self, pos: usize, offset: usize) -> u8 {
+        let storage = self.storage();
+        storage[(pos % storage.len() + offset) % storage.len()].load(Ordering::Relaxed)
+    }
+
+    fn store(&self, pos: usize, bytes: &[u8]) {
+        let storage = self.storage();
+        for (i, byte) in bytes.iter().enumerate() {
+            storage[(pos % storage.len() + i) % storage.len()].store(*byte, Ordering::Relaxed);
+        }
+    }
+
+    fn load_len(&self, pos: usize) -> usize {
+        let mut len = [0; HEADER];
+        for (i, byte) in len.iter_mut().enumerate() {
+            *byte = self.load(pos, i);
+        }
+        u32::from_le_bytes(len) as usize
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    fn ring(capacity: usize, policy: OverflowPolicy) -> RingBuffer {
+        let ring = RingBuffer::new();
+        let storage: Box<[AtomicU8]> = (0..capacity).map(|_| AtomicU8::new(0)).collect();
+        ring.set_policy(policy);
+        // SAFETY: the buffer isn't shared yet.
+        unsafe { ring.set_storage(Box::leak(storage)) };
+        ring
+    }
+
+    fn log(ring: &RingBuffer, frame: &[u8]) -> Option<usize> {
+        // SAFETY: the buffer isn't shared, so it needs no lock.
+        unsafe {
+            ring.begin_frame();
+            ring.write(frame);
+            ring.commit_frame()
+        }
+    }
+
+    fn pop(ring: &RingBuffer) -> Option<Vec<u8>> {
+        let mut frame = Vec::new();
+        ring.pop(&mut frame).then_some(frame)
+    }
+
+    #[test]
+    fn frames_come_out_in_order() {
+        let ring = ring(64, OverflowPolicy::DropOldest);
+        assert_eq!(log(&ring, b"first"), Some(5));
+        assert_eq!(log(&ring, b"second"), Some(6));
+        assert_eq!(ring.used(), 2 * HEADER + 11);
+
+        assert_eq!(pop(&ring).as_deref(), Some(&b"first"[..]));
+        assert_eq!(pop(&ring).as_deref(), Some(&b"second"[..]));
+        assert_eq!(pop(&ring), None);
+        assert_eq!(ring.take_dropped(), 0);
+    }
+
+    #[test]
+    fn pops_into_a_buffer() {
+        let ring = ring(64, OverflowPolicy::DropOldest);
+        log(&ring, b"too long");
+        log(&ring, b"short");
+
+        let mut buf = [0; 5];
+        assert_eq!(ring.pop_into(&mut buf), Some(5));
+        assert_eq!(&buf, b"short");
+        assert_eq!(ring.pop_into(&mut buf), None);
+    }
+
+    #[test]
+    fn empty_frames_are_not_committed() {
+        let ring = ring(64, OverflowPolicy::DropOldest);
+        assert_eq!(log(&ring, b""), Some(0));
+        assert_eq!(ring.used(), 0);
+        assert_eq!(pop(&ring), None);
+    }
+
+    #[test]
+    fn drop_oldest_makes_room() {
+        let ring = ring(2 * (HEADER + 4), OverflowPolicy::DropOldest);
+        for frame in [b"aaaa", b"bbbb", b"cccc"] {
+            assert_eq!(log(&ring, frame), Some(4));
+        }
+
+        assert_eq!(ring.take_dropped(), 1);
+        assert_eq!(pop(&ring).as_deref(), Some(&b"bbbb"[..]));
+        assert_eq!(pop(&ring).as_deref(), Some(&b"cccc"[..]));
+        assert_eq!(pop(&ring), None);
+    }
+
+    #[test]
+    fn drop_newest_keeps_buffered_frames() {
+        let ring = ring(2 * (HEADER + 4), OverflowPolicy::DropNewest);
+        assert_eq!(log(&ring, b"aaaa"), Some(4));
+        assert_eq!(log(&ring, b"bbbb"), Some(4));
+        assert_eq!(log(&ring, b"cccc"), None);
+
+        assert_eq!(ring.take_dropped(), 1);
+        assert_eq!(pop(&ring).as_deref(), Some(&b"aaaa"[..]));
+        // The dropped frame left no trace.
+        assert_eq!(log(&ring, b"dddd"), Some(4));
+        assert_eq!(pop(&ring).as_deref(), Some(&b"bbbb"[..]));
+        assert_eq!(pop(&ring).as_deref(), Some(&b"dddd"[..]));
+    }
+
+    #[test]
+    fn frames_larger_than_the_buffer_are_dropped() {
+        let ring = ring(16, OverflowPolicy::DropOldest);
+        assert_eq!(log(&ring, b"kept"), Some(4));
+        assert_eq!(log(&ring, &[0; 16]), None);
+
+        assert_eq!(ring.take_dropped(), 1);
+        assert_eq!(pop(&ring).as_deref(), Some(&b"kept"[..]));
+    }
+
+    #[test]
+    fn block_without_consumer_drops() {
+        let ring = ring(HEADER + 4, OverflowPolicy::Block);
+        assert_eq!(log(&ring, b"aaaa"), Some(4));
+        assert_eq!(log(&ring, b"bbbb"), None);
+        assert_eq!(pop(&ring).as_deref(), Some(&b"aaaa"[..]));
+    }
+
+    #[test]
+    fn block_waits_for_the_consumer() {
+        let ring: &'static RingBuffer =
+            Box::leak(Box::new(ring(HEADER + 4, OverflowPolicy::Block)));
+        ring.consumer.store(true, Ordering::Release);
+        assert_eq!(log(ring, b"aaaa"), Some(4));
+
+        let consumer = thread::spawn(|| {
+            thread::sleep(std::time::Duration::from_millis(50));
+            pop(ring)
+        });
+        assert_eq!(log(ring, b"bbbb"), Some(4));
+
+        assert_eq!(consumer.join().unwrap().as_deref(), Some(&b"aaaa"[..]));
+        assert_eq!(pop(ring).as_deref(), Some(&b"bbbb"[..]));
+        assert_eq!(ring.take_dropped(), 0);
+    }
+
+    #[test]
+    fn resize_keeps_the_newest_frames() {
+        let ring = ring(64, OverflowPolicy::DropOldest);
+        for frame in [b"aaaa", b"bbbb", b"cccc"] {
+            log(&ring, frame);
+        }
+
+        // SAFETY: the buffer isn't shared, and has no consumer.
+        unsafe { ring.resize(2 * (HEADER + 4)) };
+        assert_eq!(ring.capacity(), 2 * (HEADER + 4));
+        assert_eq!(pop(&ring).as_deref(), Some(&b"bbbb"[..]));
+        assert_eq!(pop(&ring).as_deref(), Some(&b"cccc"[..]));
+        assert_eq!(pop(&ring), None);
+    }
+
+    #[test]
+    fn positions_wrap_around() {
+        // Not a power of two, so positions can't simply overflow.
+        let ring = ring(15, OverflowPolicy::DropOldest);
+        let start = ring.wrap() - 7;
+        for position in [&ring.head, &ring.tail] {
+            position.store(start, Ordering::Relaxed);
+        }
+
+        for i in 0..10_u8 {
+            assert_eq!(log(&ring, &[i; 3]), Some(3));
+            assert_eq!(ring.used(), HEADER + 3);
+            assert_eq!(pop(&ring), Some(vec![i; 3]));
+        }
+        assert!(ring.head() < start);
+        assert!(ring.is_behind(start, ring.head()));
+        assert!(!ring.is_behind(ring.head(), start));
+    }
+}
@@ -0,0 +1,632 @@
+//! The TCP server that hands connections to the logger.
+
+use crate::{
+    client::{BoxTransport, Client},
+    config::{ServerConfig, SlowClientPolicy},
+    error::{self, Error},
+    filter::{self, Filter},
+    protocol, stats,
+    transport::Accepted,
+    writer::{self, PENDING_CLIENTS},
+};
+#[cfg(unix)]
+use socket2::SockAddr;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+#[cfg(unix)]
+use std::{
+    fs,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+};
+use std::{
+    io, mem,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How long shutting down waits to connect to a listener to wake it.
+const WAKE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long accepting first waits to retry after failing.
+const ACCEPT_BACKOFF_MIN: Duration = Duration::from_millis(10);
+
+/// The longest accepting waits to retry, while it keeps failing.
+const ACCEPT_BACKOFF_MAX: Duration = Duration::from_secs(1);
+
+/// The most time between keepalive probes, once a connection has been idle
+/// for the configured time.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A bound log server, listening on TCP and optionally a Unix domain socket.
+///
+/// ```rust,no_run
+/// use defmt_logger_tcp::{Server, ServerConfig};
+/// use std::thread;
+///
+/// let config = ServerConfig::builder().port(0).build();
+/// let server = Server::bind(config)?;
+/// println!("serving logs on {:?}", server.local_addrs()?);
+///
+/// thread::spawn(move || server.serve());
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Server {
+    config: ServerConfig,
+    listeners: Vec<Listener>,
+}
+
+#[derive(Debug)]
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixSocket),
+}
+
+/// A Unix domain socket listener, whose socket file is removed on drop.
+#[cfg(unix)]
+#[derive(Debug)]
+struct UnixSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+#[cfg(unix)]
+impl Drop for UnixSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+impl Server {
+    /// Binds the addresses described by `config`.
+    pub fn bind(config: ServerConfig) -> Result<Self, Error> {
+        let addrs = config.resolve()?;
+
+        let mut listeners = Vec::with_capacity(addrs.len());
+        let mut bound = Vec::new();
+        let mut assigned_port = None;
+        for mut alternatives in addrs {
+            // Listeners on port 0 share the port picked for the first one.
+            for addr in &mut alternatives {
+                if let (0, Some(port)) = (addr.port(), assigned_port) {
+                    addr.set_port(port);
+                }
+            }
+            // Another host may have resolved to an address already bound.
+            alternatives.retain(|addr| !bound.contains(addr));
+            if alternatives.is_empty() {
+                continue;
+            }
+
+            let (mut addr, listener) = bind_first(&alternatives, config.dual_stack)?;
+            if addr.port() == 0 {
+                let port = listener
+                    .local_addr()
+                    .map_err(|source| Error::Bind { addr, source })?
+                    .port();
+                assigned_port = Some(port);
+                addr.set_port(port);
+            }
+            bound.push(addr);
+            listeners.push(Listener::Tcp(listener));
+        }
+
+        #[cfg(unix)]
+        if let Some(path) = config.unix_path() {
+            let listener =
+                bind_unix(&path, config.unix_mode).map_err(|source| Error::BindUnix {
+                    path: path.clone(),
+                    source,
+                })?;
+            listeners.push(Listener::Unix(UnixSocket { listener, path }));
+        }
+
+        if listeners.is_empty() {
+            return Err(Error::NothingToListenOn);
+        }
+
+        if !config.filter.is_empty() {
+            filter::load_frames();
+        }
+
+        Ok(Self { config, listeners })
+    }
+
+    /// Returns the TCP addresses the server is listening on.
+    ///
+    /// This is how the port is discovered when binding to port `0`.
+    pub fn local_addrs(&self) -> io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| match listener {
+                Listener::Tcp(listener) => Some(listener.local_addr()),
+                #[cfg(unix)]
+                Listener::Unix(_) => None,
+            })
+            .collect()
+    }
+
+    /// Returns the path of the Unix domain socket the server is listening
+    /// on, if any.
+    #[cfg(unix)]
+    pub fn unix_path(&self) -> Option<&Path> {
+        self.listeners.iter().find_map(|listener| match listener {
+            Listener::Unix(socket) => Some(socket.path.as_path()),
+            Listener::Tcp(_) => None,
+        })
+    }
+
+    /// Accepts connections and hands them to the logger, blocking forever.
+    ///
+    /// This only returns if the server's threads can't be started. Failed
+    /// accepts are retried, and reported to the
+    /// [error hook](crate::set_error_hook).
+    pub fn serve(self) -> Result<(), Error> {
+        let Self {
+            config,
+            mut listeners,
+        } = self;
+
+        writer::start(&config)?;
+        let welcome = Welcome::new(config);
+
+        // The first listener is served on the calling thread.
+        let first = listeners.remove(0);
+        for listener in listeners {
+            let welcome = welcome.clone();
+            thread::Builder::new()
+                .name("defmt-logger-tcp-accept".into())
+                .spawn(move || listener.accept_loop(&welcome))
+                .map_err(Error::Spawn)?;
+        }
+
+        first.accept_loop(&welcome);
+        Ok(())
+    }
+
+    /// Accepts connections on background threads, returning a handle to shut
+    /// the server down with.
+    ///
+    /// ```rust,no_run
+    /// use defmt_logger_tcp::{Server, ServerConfig};
+    /// use std::time::Duration;
+    ///
+    /// let server = Server::bind(ServerConfig::default())?.start()?;
+    ///
+    /// defmt::error!("shutting down");
+    /// server.shutdown(Duration::from_secs(1));
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn start(self) -> Result<ServerHandle, Error> {
+        let wakers = self.listeners.iter().filter_map(Listener::waker).collect();
+
+        writer::start(&self.config)?;
+        let welcome = Welcome::new(self.config);
+        let state = welcome.state.clone();
+        let threads = self
+            .listeners
+            .into_iter()
+            .map(|listener| {
+                let welcome = welcome.clone();
+                thread::Builder::new()
+                    .name("defmt-logger-tcp-accept".into())
+                    .spawn(move || listener.accept_loop(&welcome))
+            })
+            .collect::<io::Result<_>>()
+            .map_err(Error::Spawn)?;
+
+        Ok(ServerHandle {
+            state,
+            threads,
+            wakers,
+            shutdown_on_drop: None,
+        })
+    }
+}
+
+/// A running server, returned by [`Server::start`].
+///
+/// Dropping the handle leaves the server running, unless
+/// [`shutdown_on_drop`](Self::shutdown_on_drop) is used.
+#[derive(Debug)]
+pub struct ServerHandle {
+    state: Arc<ServerState>,
+    threads: Vec<JoinHandle<()>>,
+    wakers: Vec<Waker>,
+    shutdown_on_drop: Option<Duration>,
+}
+
+impl ServerHandle {
+    /// Stops accepting connections, sends the frames logged so far to every
+    /// client, then disconnects the clients this server accepted.
+    ///
+    /// Waits at most `timeout` altogether: clients that haven't been sent
+    /// everything by then are disconnected anyway. Transports added with
+    /// [`add_transport`](crate::add_transport) keep receiving frames.
+    pub fn shutdown(mut self, timeout: Duration) {
+        // Dropping the handle mustn't stop the server again.
+        self.shutdown_on_drop.take();
+        self.stop(timeout);
+    }
+
+    /// Shuts the server down when the handle is dropped, waiting at most
+    /// `timeout`, so the last lines logged before `main` returns reach the
+    /// clients.
+    ///
+    /// ```rust,no_run
+    /// use std::time::Duration;
+    ///
+    /// fn main() -> std::io::Result<()> {
+    ///     let _server = defmt_logger_tcp::start()?.shutdown_on_drop(Duration::from_secs(1));
+    ///
+    ///     defmt::error!("about to exit");
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn shutdown_on_drop(mut self, timeout: Duration) -> Self {
+        self.shutdown_on_drop = Some(timeout);
+        self
+    }
+
+    fn stop(&mut self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+
+        // Accepts block, so each listener is woken with a connection of its
+        // own, which is dropped.
+        self.state.stopping.store(true, Ordering::Release);
+        for waker in &self.wakers {
+            waker.wake();
+        }
+
+        // A listener that can't be woken is left to stop on its next
+        // connection.
+        for thread in mem::take(&mut self.threads) {
+            while !thread.is_finished() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        writer::wait_sent(deadline.saturating_duration_since(Instant::now()));
+
+        // The writer thread drops the clients, and with them their handles
+        // on the state.
+        self.state.closing.store(true, Ordering::Release);
+        while Arc::strong_count(&self.state) > 1 && Instant::now() < deadline {
+            writer::notify();
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        if let Some(timeout) = self.shutdown_on_drop.take() {
+            self.stop(timeout);
+        }
+    }
+}
+
+/// The state shared by a server's accept loops, its handle, and the clients
+/// it accepted.
+#[derive(Debug, Default)]
+pub(crate) struct ServerState {
+    /// Set when the accept loops should stop.
+    stopping: AtomicBool,
+    /// Set once the clients should be disconnected.
+    closing: AtomicBool,
+    /// The number of clients connected.
+    connections: AtomicUsize,
+}
+
+impl ServerState {
+    pub(crate) fn is_closing(&self) -> bool {
+        self.closing.load(Ordering::Acquire)
+    }
+
+    /// Counts a new client, returning `false` if `max` are already
+    /// connected.
+    fn connect(&self, max: usize) -> bool {
+        self.connections
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |connections| {
+                (connections < max).then_some(connections + 1)
+            })
+            .is_ok()
+    }
+
+    /// Counts a client that was dropped.
+    pub(crate) fn disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::AcqRel);
+        stats::disconnected();
+    }
+}
+
+/// Connects to a listener to wake it from a blocking accept.
+#[derive(Debug)]
+enum Waker {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Waker {
+    fn wake(&self) {
+        let _ = match self {
+            Self::Tcp(addr) => TcpStream::connect_timeout(addr, WAKE_TIMEOUT).map(drop),
+            #[cfg(unix)]
+            Self::Unix(path) => UnixStream::connect(path).map(drop),
+        };
+    }
+}
+
+/// Binds the first of `alternatives` that can be, returning the error for
+/// the last one if none can.
+fn bind_first(
+    alternatives: &[SocketAddr],
+    dual_stack: bool,
+) -> Result<(SocketAddr, TcpListener), Error> {
+    let mut error = None;
+    for &addr in alternatives {
+        match bind_listener(addr, dual_stack) {
+            Ok(listener) => return Ok((addr, listener)),
+            Err(source) => error = Some(Error::Bind { addr, source }),
+        }
+    }
+    Err(error.unwrap_or(Error::NothingToListenOn))
+}
+
+fn bind_listener(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() && dual_stack {
+        // The IPv4 side gets its own listener.
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Binds a Unix domain socket, replacing a stale socket left at `path`.
+#[cfg(unix)]
+fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    let addr = SockAddr::unix(path)?;
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None)?;
+    match socket.bind(&addr) {
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && is_stale(path) => {
+            fs::remove_file(path)?;
+            socket.bind(&addr)?;
+        }
+        result => result?,
+    }
+
+    // Connections are refused until the socket listens, so nobody can
+    // connect before its permissions are restricted.
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    socket.listen(128)?;
+    Ok(socket.into())
+}
+
+/// Returns `true` if `path` is a socket nothing is listening on.
+///
+/// Anything else, including a socket another process is still serving on,
+/// is left alone.
+#[cfg(unix)]
+fn is_stale(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket())
+        && UnixStream::connect(path).is_err_and(|e| e.kind() == io::ErrorKind::ConnectionRefused)
+}
+
+impl Listener {
+    /// Accepts connections, setting each one up as `welcome` says, until the
+    /// server is stopped.
+    ///
+    /// Failed accepts are reported and retried, backing off while they keep
+    /// failing, eg. until file descriptors are freed.
+    fn accept_loop(&self, welcome: &Welcome) {
+        let mut backoff = ACCEPT_BACKOFF_MIN;
+        loop {
+            let accepted = self.accept(welcome.keepalive.as_ref());
+            if welcome.state.stopping.load(Ordering::Acquire) {
+                break;
+            }
+
+            match accepted {
+                Ok(Ok((transport, peer))) => {
+                    backoff = ACCEPT_BACKOFF_MIN;
+                    let connected = welcome.state.connect(welcome.max_connections);
+                    stats::accepted(!connected);
+                    if connected {
+                        add_client(transport, peer, welcome);
+                    } else {
+                        error::report(Error::TooManyConnections);
+                    }
+                }
+                Ok(Err(e)) => error::report(Error::Connection(e)),
+                // The connection was given up on before it was accepted.
+                Err(e) if is_aborted(&e) => {}
+                Err(e) => {
+                    error::report(Error::Accept(e));
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(ACCEPT_BACKOFF_MAX);
+                }
+            }
+        }
+    }
+
+    /// Accepts a connection and returns it with the address of its peer,
+    /// or returns the error accepting it, or the error setting it up.
+    fn accept(
+        &self,
+        keepalive: Option<&TcpKeepalive>,
+    ) -> io::Result<io::Result<(BoxTransport, String)>> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                let set_up = || {
+                    if let Some(keepalive) = keepalive {
+                        SockRef::from(&stream).set_tcp_keepalive(keepalive)?;
+                    }
+                    // Writes are retried by the writer thread rather than
+                    // blocking it.
+                    stream.set_nonblocking(true)
+                };
+                Ok(set_up()
+                    .map(|()| (Box::new(Accepted(stream)) as BoxTransport, peer.to_string())))
+            }
+            #[cfg(unix)]
+            Self::Unix(socket) => {
+                // Clients rarely bind their end of a Unix socket, so they
+                // are told apart by the socket they connected to.
+                let (stream, _) = socket.listener.accept()?;
+                let peer = format!("unix:{}", socket.path.display());
+                Ok(stream
+                    .set_nonblocking(true)
+                    .map(|()| (Box::new(Accepted(stream)) as BoxTransport, peer)))
+            }
+        }
+    }
+
+    fn waker(&self) -> Option<Waker> {
+        match self {
+            Self::Tcp(listener) => {
+                let mut addr = listener.local_addr().ok()?;
+                // A wildcard address can't be connected to, but loopback
+                // reaches the same listener.
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(match addr {
+                        SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                        SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                    });
+                }
+                Some(Waker::Tcp(addr))
+            }
+            #[cfg(unix)]
+            Self::Unix(socket) => Some(Waker::Unix(socket.path.clone())),
+        }
+    }
+}
+
+/// How each client is set up when it connects.
+#[derive(Clone)]
+struct Welcome {
+    /// Sent before any frames.
+    handshake: Option<Vec<u8>>,
+    /// The filter the client starts with.
+    filter: Filter,
+    max_connections: usize,
+    queue_limit: Option<usize>,
+    slow_client_policy: SlowClientPolicy,
+    /// Set on TCP connections.
+    keepalive: Option<TcpKeepalive>,
+    state: Arc<ServerState>,
+}
+
+impl Welcome {
+    fn new(config: ServerConfig) -> Self {
+        Self {
+            handshake: config.handshake.then(protocol::handshake),
+            filter: config.filter,
+            max_connections: config.max_connections,
+            queue_limit: config.client_queue_bytes,
+            slow_client_policy: config.slow_client_policy,
+            keepalive: config.keepalive.map(keepalive),
+            state: Arc::default(),
+        }
+    }
+}
+
+/// Returns keepalive settings that start probing once a connection has been
+/// idle for `idle`.
+fn keepalive(idle: Duration) -> TcpKeepalive {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(
+        target_os = "android",
+        target_os = "freebsd",
+        target_os = "ios",
+        target_os = "linux",
+        target_os = "macos",
+        target_os = "netbsd",
+        windows,
+    ))]
+    let keepalive = keepalive.with_interval(idle.min(KEEPALIVE_INTERVAL));
+    keepalive
+}
+
+fn add_client(transport: BoxTransport, peer: String, welcome: &Welcome) {
+    let mut client = Client::new(transport, Some(peer));
+    client.set_filter(welcome.filter.clone());
+    client.set_server(welcome.state.clone());
+    client.set_limits(welcome.queue_limit, welcome.slow_client_policy);
+    if let Some(handshake) = &welcome.handshake {
+        client.push_control(handshake);
+    }
+
+    PENDING_CLIENTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(client);
+    writer::notify();
+}
+
+/// Returns `true` if accepting failed because of the connection, rather than
+/// the listener.
+fn is_aborted(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn falls_back_to_the_next_address() {
+        let taken = bind_listener(([127, 0, 0, 1], 0).into(), false).unwrap();
+        let taken = taken.local_addr().unwrap();
+        let free = SocketAddr::from(([127, 0, 0, 1], 0));
+
+        let (addr, listener) = bind_first(&[taken, free], false).unwrap();
+        assert_eq!(addr, free);
+        assert_ne!(listener.local_addr().unwrap(), taken);
+
+        let e = bind_first(&[taken], false).unwrap_err();
+        assert!(matches!(e, Error::Bind { addr, .. } if addr == taken));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn binds_unix_sockets_with_their_mode() {
+        let path =
+            std::env::temp_dir().join(format!("defmt-logger-tcp-{}.sock", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let listener = bind_unix(&path, Some(0o600)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        UnixStream::connect(&path).unwrap();
+
+        // The socket left behind is replaced, once nothing listens on it.
+        assert!(bind_unix(&path, None).is_err());
+        drop(listener);
+        let _listener = bind_unix(&path, None).unwrap();
+        UnixStream::connect(&path).unwrap();
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,390 @@
+//! Counters describing what the logger has done, and serving them to
+//! Prometheus.
+
+use crate::{
+    error::{self, Error},
+    RING,
+};
+use std::{
+    fmt::{self, Write as _},
+    io::{self, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, PoisonError, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+/// How long a metrics request may take to arrive, so a client that doesn't
+/// send one can't hold up the others.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The most of a metrics request that is read.
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+static FRAMES_LOGGED: AtomicU64 = AtomicU64::new(0);
+static BYTES_LOGGED: AtomicU64 = AtomicU64::new(0);
+static FRAMES_DROPPED: AtomicU64 = AtomicU64::new(0);
+static HIGH_WATER: AtomicUsize = AtomicUsize::new(0);
+static ENCODE_NANOS: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+static CONNECTIONS_ACCEPTED: AtomicU64 = AtomicU64::new(0);
+static CONNECTIONS_REFUSED: AtomicU64 = AtomicU64::new(0);
+
+/// The counters of each client, dropped along with the client.
+static CLIENTS: Mutex<Vec<Weak<ClientCounters>>> = Mutex::new(Vec::new());
+
+/// A per client metric: its name, help text, and value.
+type ClientMetric = (&'static str, &'static str, fn(&ClientStats) -> u64);
+
+/// A snapshot of what the logger has done since the process started.
+///
+/// ```rust
+/// defmt::println!("Hello, world!");
+///
+/// let stats = defmt_logger_tcp::stats();
+/// println!("{} frames logged, {} dropped", stats.frames_logged, stats.frames_dropped);
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Stats {
+    /// Frames logged into the buffer.
+    pub frames_logged: u64,
+    /// Bytes logged into the buffer, before encoding.
+    pub bytes_logged: u64,
+    /// Frames dropped, from the buffer or for a client, as
+    /// [`dropped_frames`] counts them.
+    pub frames_dropped: u64,
+    /// The most bytes the buffer has held at once.
+    pub buffer_high_water: usize,
+    /// The size of the buffer in bytes.
+    pub buffer_capacity: usize,
+    /// Time the writer thread spent encoding frames.
+    pub encode_time: Duration,
+    /// Clients connected to a server.
+    pub connections: usize,
+    /// Connections accepted by a server.
+    pub connections_accepted: u64,
+    /// Connections closed straight away, because too many clients were
+    /// connected.
+    pub connections_refused: u64,
+    /// The clients and transports frames are sent to.
+    pub clients: Vec<ClientStats>,
+}
+
+/// What has been sent to one client or transport.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ClientStats {
+    /// Tells clients apart, including ones with the same peer.
+    pub id: u64,
+    /// The address of a client a server accepted, or `None` for a transport.
+    pub peer: Option<String>,
+    /// Bytes written to the client.
+    pub bytes_sent: u64,
+    /// Frames the client missed.
+    pub frames_dropped: u64,
+}
+
+/// Returns a snapshot of the logger's counters.
+pub fn stats() -> Stats {
+    let clients = {
+        let mut clients = CLIENTS.lock().unwrap_or_else(PoisonError::into_inner);
+        clients.retain(|client| client.strong_count() > 0);
+        clients
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|client| ClientStats {
+                id: client.id,
+                peer: client.peer.clone(),
+                bytes_sent: client.bytes_sent.load(Ordering::Relaxed),
+                frames_dropped: client.frames_dropped.load(Ordering::Relaxed),
+            })
+            .collect()
+    };
+
+    Stats {
+        frames_logged: FRAMES_LOGGED.load(Ordering::Relaxed),
+        bytes_logged: BYTES_LOGGED.load(Ordering::Relaxed),
+        frames_dropped: FRAMES_DROPPED.load(Ordering::Relaxed),
+        buffer_high_water: HIGH_WATER.load(Ordering::Relaxed),
+        buffer_capacity: RING.capacity(),
+        encode_time: Duration::from_nanos(ENCODE_NANOS.load(Ordering::Relaxed)),
+        connections: CONNECTIONS.load(Ordering::Relaxed),
+        connections_accepted: CONNECTIONS_ACCEPTED.load(Ordering::Relaxed),
+        connections_refused: CONNECTIONS_REFUSED.load(Ordering::Relaxed),
+        clients,
+    }
+}
+
+/// Returns the number of frames dropped since the process started.
+///
+/// Frames are dropped when the buffer is full, as the
+/// [`OverflowPolicy`](crate::OverflowPolicy) says, or when a client falls
+/// behind and has frames skipped. Either way, the clients that miss frames
+/// are sent a frame saying how many, which decoders show like any other.
+pub fn dropped_frames() -> u64 {
+    FRAMES_DROPPED.load(Ordering::Relaxed)
+}
+
+impl Stats {
+    /// Formats the counters in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut text = String::new();
+        #[rustfmt::skip]
+        let metrics: [(&str, &str, &str, &dyn fmt::Display); 9] = [
+            ("frames_logged_total", "counter", "Frames logged into the buffer.", &self.frames_logged),
+            ("bytes_logged_total", "counter", "Bytes logged into the buffer.", &self.bytes_logged),
+            ("frames_dropped_total", "counter", "Frames dropped, from the buffer or for a client.", &self.frames_dropped),
+            ("buffer_high_water_bytes", "gauge", "The most bytes the buffer has held at once.", &self.buffer_high_water),
+            ("buffer_capacity_bytes", "gauge", "The size of the buffer.", &self.buffer_capacity),
+            ("encode_seconds_total", "counter", "Time spent encoding frames.", &self.encode_time.as_secs_f64()),
+            ("connections", "gauge", "Clients connected to a server.", &self.connections),
+            ("connections_accepted_total", "counter", "Connections accepted by a server.", &self.connections_accepted),
+            ("connections_refused_total", "counter", "Connections closed because too many clients were connected.", &self.connections_refused),
+        ];
+        for (name, kind, help, value) in metrics {
+            header(&mut text, name, kind, help);
+            let _ = writeln!(text, "defmt_logger_tcp_{name} {value}");
+        }
+
+        #[rustfmt::skip]
+        let client_metrics: [ClientMetric; 2] = [
+            ("client_bytes_sent_total", "Bytes written to a client.", |client| client.bytes_sent),
+            ("client_frames_dropped_total", "Frames a client missed.", |client| client.frames_dropped),
+        ];
+        for (name, help, value) in client_metrics {
+            header(&mut text, name, "counter", help);
+            for client in &self.clients {
+                let peer = escape(client.peer.as_deref().unwrap_or_default());
+                let _ = writeln!(
+                    text,
+                    "defmt_logger_tcp_{name}{{id=\"{}\",peer=\"{peer}\"}} {}",
+                    client.id,
+                    value(client),
+                );
+            }
+        }
+        text
+    }
+}
+
+fn header(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP defmt_logger_tcp_{name} {help}");
+    let _ = writeln!(text, "# TYPE defmt_logger_tcp_{name} {kind}");
+}
+
+/// Escapes a Prometheus label value.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Serves [`stats`] over HTTP on `addr`, in the Prometheus text format, for
+/// Prometheus to scrape. Returns the address served on, which tells the port
+/// picked when binding to port `0`.
+///
+/// Every request is answered with the metrics, whatever its path. The
+/// metrics are served on a background thread for the rest of the process.
+///
+/// ```rust,no_run
+/// let addr = defmt_logger_tcp::serve_metrics("127.0.0.1:9100")?;
+/// println!("metrics on http://{addr}/metrics");
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn serve_metrics(addr: impl ToSocketAddrs) -> Result<SocketAddr, Error> {
+    let addrs: Vec<SocketAddr> = addr
+        .to_socket_addrs()
+        .map_err(|source| Error::Resolve {
+            host: "the metrics address".into(),
+            source,
+        })?
+        .collect();
+    let Some(&first) = addrs.first() else {
+        return Err(Error::NothingToListenOn);
+    };
+    let listener = TcpListener::bind(&addrs[..]).map_err(|source| Error::Bind {
+        addr: first,
+        source,
+    })?;
+    let local_addr = listener.local_addr().map_err(|source| Error::Bind {
+        addr: first,
+        source,
+    })?;
+
+    thread::Builder::new()
+        .name("defmt-logger-tcp-metrics".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let served = stream.and_then(answer_metrics);
+                if let Err(e) = served {
+                    error::report(Error::Connection(e));
+                }
+            }
+        })
+        .map_err(Error::Spawn)?;
+    Ok(local_addr)
+}
+
+/// Reads an HTTP request, and answers it with the metrics.
+fn answer_metrics(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") && request.len() < MAX_REQUEST_BYTES {
+        match stream.read(&mut buf)? {
+            0 => break,
+            read => request.extend_from_slice(&buf[..read]),
+        }
+    }
+
+    let body = stats().to_prometheus();
+    write!(
+        stream,
+        "HTTP/1.1 200 OK\r\n\
+         Content-Type: text/plain; version=0.0.4\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\
+         \r\n\
+         {body}",
+        body.len(),
+    )
+}
+
+/// The counters of one client, shared between the writer thread and
+/// [`stats`].
+#[derive(Debug)]
+pub(crate) struct ClientCounters {
+    id: u64,
+    peer: Option<String>,
+    bytes_sent: AtomicU64,
+    frames_dropped: AtomicU64,
+}
+
+impl ClientCounters {
+    /// Registers the counters of a new client, with the address of its peer
+    /// if it has one.
+    pub(crate) fn register(peer: Option<String>) -> Arc<Self> {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+        let counters = Arc::new(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer,
+            bytes_sent: AtomicU64::new(0),
+            frames_dropped: AtomicU64::new(0),
+        });
+        CLIENTS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(Arc::downgrade(&counters));
+        counters
+    }
+
+    pub(crate) fn sent(&self, bytes: usize) {
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Counts frames the client missed.
+    pub(crate) fn dropped(&self, frames: u32) {
+        self.frames_dropped
+            .fetch_add(u64::from(frames), Ordering::Relaxed);
+    }
+}
+
+/// Counts a frame committed to the buffer, of `bytes` bytes, after which the
+/// buffer holds `used` bytes.
+pub(crate) fn logged(bytes: usize, used: usize) {
+    FRAMES_LOGGED.fetch_add(1, Ordering::Relaxed);
+    BYTES_LOGGED.fetch_add(bytes as u64, Ordering::Relaxed);
+    HIGH_WATER.fetch_max(used, Ordering::Relaxed);
+}
+
+/// Adds to the total of dropped frames.
+pub(crate) fn frames_dropped(frames: u32) {
+    FRAMES_DROPPED.fetch_add(u64::from(frames), Ordering::Relaxed);
+}
+
+pub(crate) fn encoded(time: Duration) {
+    ENCODE_NANOS.fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+}
+
+/// Counts a connection a server accepted, and whether it was refused.
+pub(crate) fn accepted(refused: bool) {
+    CONNECTIONS_ACCEPTED.fetch_add(1, Ordering::Relaxed);
+    if refused {
+        CONNECTIONS_REFUSED.fetch_add(1, Ordering::Relaxed);
+    } else {
+        CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts a client of a server going away.
+pub(crate) fn disconnected() {
+    CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_prometheus_metrics() {
+        let stats = Stats {
+            frames_logged: 12,
+            encode_time: Duration::from_millis(1500),
+            clients: vec![
+                ClientStats {
+                    id: 1,
+                    peer: Some("127.0.0.1:50000".into()),
+                    bytes_sent: 300,
+                    frames_dropped: 2,
+                },
+                ClientStats {
+                    id: 2,
+                    peer: None,
+                    bytes_sent: 400,
+                    frames_dropped: 0,
+                },
+            ],
+            ..Stats::default()
+        };
+        let text = stats.to_prometheus();
+
+        let lines: Vec<_> = text.lines().collect();
+        assert!(lines.contains(&"# TYPE defmt_logger_tcp_frames_logged_total counter"));
+        assert!(lines.contains(&"defmt_logger_tcp_frames_logged_total 12"));
+        assert!(lines.contains(&"defmt_logger_tcp_encode_seconds_total 1.5"));
+
+        let sent = text
+            .split_once("# TYPE defmt_logger_tcp_client_bytes_sent_total counter\n")
+            .unwrap()
+            .1;
+        assert!(sent.starts_with(concat!(
+            "defmt_logger_tcp_client_bytes_sent_total{id=\"1\",peer=\"127.0.0.1:50000\"} 300\n",
+            "defmt_logger_tcp_client_bytes_sent_total{id=\"2\",peer=\"\"} 400\n",
+            "# HELP defmt_logger_tcp_client_frames_dropped_total",
+        )));
+        assert!(lines.contains(
+            &"defmt_logger_tcp_client_frames_dropped_total{id=\"1\",peer=\"127.0.0.1:50000\"} 2"
+        ));
+    }
+
+    #[test]
+    fn escapes_peer_labels() {
+        let stats = Stats {
+            clients: vec![ClientStats {
+                id: 3,
+                peer: Some("/tmp/\"my\\app\"\n.sock".into()),
+                ..ClientStats::default()
+            }],
+            ..Stats::default()
+        };
+        assert!(stats.to_prometheus().contains(
+            r#"defmt_logger_tcp_client_bytes_sent_total{id="3",peer="/tmp/\"my\\app\"\n.sock"} 0"#
+        ));
+    }
+}
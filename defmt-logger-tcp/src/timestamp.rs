@@ -0,0 +1,85 @@
+//! Timestamps for log frames, selected with the `timestamp-*` features.
+
+#[cfg(any(
+    all(feature = "timestamp-uptime", feature = "timestamp-unix"),
+    all(feature = "timestamp-uptime", feature = "timestamp-callback"),
+    all(feature = "timestamp-unix", feature = "timestamp-callback"),
+))]
+compile_error!("Multiple `timestamp-*` features are enabled. You may only enable one.");
+
+#[cfg(feature = "timestamp-uptime")]
+mod uptime {
+    use std::{sync::OnceLock, time::Instant};
+
+    static START: OnceLock<Instant> = OnceLock::new();
+
+    // Record the start time before `main` runs where the platform allows it,
+    // otherwise it is recorded when the first frame is logged.
+    #[cfg(target_os = "linux")]
+    #[used]
+    #[link_section = ".init_array"]
+    static INIT: extern "C" fn() = {
+        extern "C" fn init() {
+            start();
+        }
+        init
+    };
+
+    fn start() -> Instant {
+        *START.get_or_init(Instant::now)
+    }
+
+    fn micros() -> u64 {
+        start().elapsed().as_micros() as u64
+    }
+
+    defmt::timestamp!("{=u64:tus}", micros());
+}
+
+#[cfg(feature = "timestamp-unix")]
+mod unix {
+    use std::time::SystemTime;
+
+    fn millis() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+
+    defmt::timestamp!("{=u64:iso8601ms}", millis());
+}
+
+#[cfg(feature = "timestamp-callback")]
+mod callback {
+    use core::{
+        mem, ptr,
+        sync::atomic::{AtomicPtr, Ordering},
+    };
+
+    static CALLBACK: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+    /// Sets the function that provides the timestamp of each log frame.
+    ///
+    /// The value is displayed as a plain integer, in whatever unit the
+    /// function uses. Frames logged before this is called have a timestamp
+    /// of `0`.
+    pub fn set_timestamp_fn(f: fn() -> u64) {
+        CALLBACK.store(f as *mut (), Ordering::Release);
+    }
+
+    fn timestamp() -> u64 {
+        let f = CALLBACK.load(Ordering::Acquire);
+        if f.is_null() {
+            return 0;
+        }
+
+        // SAFETY: only ever set from a `fn() -> u64` above.
+        let f: fn() -> u64 = unsafe { mem::transmute(f) };
+        f()
+    }
+
+    defmt::timestamp!("{=u64}", timestamp());
+}
+
+#[cfg(feature = "timestamp-callback")]
+pub use callback::set_timestamp_fn;
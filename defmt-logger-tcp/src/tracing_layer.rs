@@ -0,0 +1,155 @@
+//! Logging `tracing` events as defmt frames.
+
+use crate::gap;
+use std::{
+    fmt::{self, Write},
+    mem,
+    sync::OnceLock,
+};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// A `tracing_subscriber::Layer` that logs each event as a defmt frame at
+/// the same level.
+///
+/// A frame holds the event's target, the spans it is in from the outermost
+/// with their fields, then its message and other fields, eg.
+/// `my_app::db: request{id=7}:query: slow query elapsed_ms=120`.
+///
+/// As with [`LogBridge`](crate::LogBridge), events at levels the `DEFMT_LOG`
+/// filter this crate was compiled with leaves out, which are all but `error`
+/// when it is unset, are logged with `defmt::println!` instead, with the
+/// level at the start of the message.
+///
+/// ```rust
+/// use defmt_logger_tcp::DefmtLayer;
+/// use std::thread;
+/// use tracing_subscriber::prelude::*;
+///
+/// let subscriber = tracing_subscriber::registry().with(DefmtLayer::new());
+/// tracing::subscriber::set_global_default(subscriber).unwrap();
+/// thread::spawn(defmt_logger_tcp::run);
+///
+/// tracing::error!(code = 42, "Hello from tracing!");
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct DefmtLayer;
+
+impl DefmtLayer {
+    /// Creates the layer.
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+/// The formatted fields of a span, kept in its extensions.
+struct SpanFields(String);
+
+impl<S> Layer<S> for DefmtLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut fields = FieldWriter::default();
+        attrs.record(&mut fields);
+        span.extensions_mut().insert(SpanFields(fields.fields));
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+
+        let mut extensions = span.extensions_mut();
+        if let Some(SpanFields(fields)) = extensions.get_mut::<SpanFields>() {
+            let mut writer = FieldWriter {
+                fields: mem::take(fields),
+                ..FieldWriter::default()
+            };
+            values.record(&mut writer);
+            *fields = writer.fields;
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut scope = String::new();
+        if let Some(spans) = ctx.event_scope(event) {
+            for span in spans.from_root() {
+                scope.push_str(span.name());
+                if let Some(SpanFields(fields)) = span.extensions().get::<SpanFields>() {
+                    if !fields.is_empty() {
+                        let _ = write!(scope, "{{{fields}}}");
+                    }
+                }
+                scope.push(':');
+            }
+            if !scope.is_empty() {
+                scope.push(' ');
+            }
+        }
+
+        let mut writer = FieldWriter::default();
+        event.record(&mut writer);
+        let mut message = writer.message;
+        if !writer.fields.is_empty() {
+            if !message.is_empty() {
+                message.push(' ');
+            }
+            message.push_str(&writer.fields);
+        }
+
+        // Everything is formatted before taking the defmt logger, in case a
+        // field's `Debug` implementation logs too.
+        let target = event.metadata().target();
+        let [trace, debug, info, warn, error] = *compiled_in();
+        match *event.metadata().level() {
+            Level::ERROR if error => defmt::error!("{=str}: {=str}{=str}", target, scope, message),
+            Level::WARN if warn => defmt::warn!("{=str}: {=str}{=str}", target, scope, message),
+            Level::INFO if info => defmt::info!("{=str}: {=str}{=str}", target, scope, message),
+            Level::DEBUG if debug => defmt::debug!("{=str}: {=str}{=str}", target, scope, message),
+            Level::TRACE if trace => defmt::trace!("{=str}: {=str}{=str}", target, scope, message),
+            level => defmt::println!(
+                "{=str} {=str}: {=str}{=str}",
+                level.as_str(),
+                target,
+                scope,
+                message
+            ),
+        }
+    }
+}
+
+/// Returns which levels, from `trace` to `error`, the events can be logged
+/// at.
+fn compiled_in() -> &'static [bool; 5] {
+    static COMPILED_IN: OnceLock<[bool; 5]> = OnceLock::new();
+    COMPILED_IN.get_or_init(|| gap::levels_compiled_in!())
+}
+
+/// Formats the `message` field, and the other fields as `name=value`.
+#[derive(Default)]
+struct FieldWriter {
+    message: String,
+    fields: String,
+}
+
+impl Visit for FieldWriter {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{value:?}");
+            return;
+        }
+
+        if !self.fields.is_empty() {
+            self.fields.push(' ');
+        }
+        let _ = write!(self.fields, "{}={value:?}", field.name());
+    }
+}
@@ -0,0 +1,136 @@
+//! The byte streams encoded frames are written to.
+
+/// A byte stream that encoded log frames are written to, such as a socket.
+///
+/// Writes must not block: a transport that can't accept more bytes right now
+/// returns `Ok(0)`, and the rest is retried later. Frames are only ever
+/// started at a frame boundary, and a frame that has been partly written is
+/// always finished before the next one, so a transport never needs to buffer
+/// anything itself.
+pub trait Transport {
+    /// The error returned when the transport fails. The transport is dropped
+    /// after an error.
+    type Error;
+
+    /// Writes a prefix of `bytes`, returning how many bytes were written.
+    fn write(&mut self, bytes: &[u8]) -> Result<usize, Self::Error>;
+
+    /// Flushes any bytes buffered by the transport.
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Reads bytes sent back by the other end, such as a client's requests,
+    /// returning how many were read.
+    ///
+    /// Like writes, reads must not block, returning `Ok(0)` when nothing has
+    /// been received. Transports that can't receive anything needn't
+    /// implement this.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let _ = buf;
+        Ok(0)
+    }
+}
+
+#[cfg(feature = "std")]
+pub(crate) use io::Accepted;
+
+#[cfg(feature = "std")]
+mod io {
+    use super::Transport;
+    use std::{
+        fs::File,
+        io::{self, Read, Stderr, Stdout, Write},
+        net::TcpStream,
+        sync::{Arc, Mutex, PoisonError},
+    };
+
+    /// Writes to a `std::io::Write` implementation, treating `WouldBlock` as
+    /// no progress.
+    pub(crate) fn write(writer: &mut impl Write, bytes: &[u8]) -> io::Result<usize> {
+        loop {
+            match writer.write(bytes) {
+                Ok(0) if !bytes.is_empty() => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => return Ok(written),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub(crate) fn flush(writer: &mut impl Write) -> io::Result<()> {
+        match writer.flush() {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+
+    /// Reads from a non-blocking socket, treating `WouldBlock` and the end of
+    /// the stream as nothing received. A client that has closed its side can
+    /// still be sent frames.
+    pub(crate) fn read(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match reader.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(0),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => return result,
+            }
+        }
+    }
+
+    macro_rules! impl_transport {
+        ($($ty:ty),*) => {
+            $(
+                impl Transport for $ty {
+                    type Error = io::Error;
+
+                    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+                        write(self, bytes)
+                    }
+
+                    fn flush(&mut self) -> io::Result<()> {
+                        flush(self)
+                    }
+                }
+            )*
+        };
+    }
+
+    // Sockets added as transports aren't read from, as they may block.
+    impl_transport!(File, Stdout, Stderr, TcpStream);
+
+    #[cfg(unix)]
+    impl_transport!(std::os::unix::net::UnixStream);
+
+    /// A socket the server accepted and made non-blocking, which the client
+    /// can send requests over.
+    pub(crate) struct Accepted<S>(pub(crate) S);
+
+    impl<S: Read + Write> Transport for Accepted<S> {
+        type Error = io::Error;
+
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            write(&mut self.0, bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            flush(&mut self.0)
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            read(&mut self.0, buf)
+        }
+    }
+
+    /// Collects the encoded frames in memory, eg. for tests.
+    impl Transport for Arc<Mutex<Vec<u8>>> {
+        type Error = io::Error;
+
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            let mut buffer = self.lock().unwrap_or_else(PoisonError::into_inner);
+            buffer.extend_from_slice(bytes);
+            Ok(bytes.len())
+        }
+    }
+}
@@ -0,0 +1,434 @@
+//! The background thread that sends buffered frames to clients.
+
+use crate::{
+    client::Client,
+    config::{ServerConfig, SlowClientPolicy},
+    error::{self, Error},
+    filter::Filter,
+    gap,
+    protocol::{self, Request},
+    ring::OverflowPolicy,
+    stats,
+    transport::Transport,
+    LOCK, RING,
+};
+use defmt::Encoder;
+use std::{
+    collections::VecDeque,
+    io, mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, OnceLock, PoisonError,
+    },
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+/// How long the writer sleeps when it isn't woken by a new frame.
+const IDLE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// How long the writer sleeps before retrying clients that couldn't accept
+/// everything queued for them.
+const RETRY_TIMEOUT: Duration = Duration::from_millis(5);
+
+pub(crate) static PENDING_CLIENTS: Mutex<Vec<Client>> = Mutex::new(Vec::new());
+
+static WRITER: OnceLock<Thread> = OnceLock::new();
+
+/// Settings from the first server to start, for the writer thread to take up
+/// when it was started with the defaults by an earlier transport.
+static RECONFIGURE: Mutex<Option<WriterConfig>> = Mutex::new(None);
+
+/// The ring buffer position up to which every frame has been sent to every
+/// client.
+static SENT: AtomicUsize = AtomicUsize::new(0);
+
+/// Sends log frames to `transport`, alongside any TCP clients.
+///
+/// The transport is first sent the recent history, then every frame logged
+/// from then on. It is dropped if a write fails, or if it doesn't accept any
+/// bytes for the configured write timeout.
+///
+/// This starts the writer thread if no server has, and until one does, the
+/// transport is sent frames with the default [`ServerConfig`] settings.
+///
+/// ```rust
+/// use std::sync::{Arc, Mutex};
+///
+/// let buffer = Arc::new(Mutex::new(Vec::new()));
+/// defmt_logger_tcp::add_transport(buffer.clone());
+///
+/// defmt::println!("Hello, world!");
+/// defmt::flush();
+///
+/// assert!(!buffer.lock().unwrap().is_empty());
+/// ```
+pub fn add_transport<T>(transport: T)
+where
+    T: Transport<Error = io::Error> + Send + 'static,
+{
+    add_filtered_transport(transport, Filter::new());
+}
+
+/// Sends the log frames that pass `filter` to `transport`, like
+/// [`add_transport`].
+///
+/// ```rust,no_run
+/// use defmt_logger_tcp::{FileSink, Filter, Level};
+///
+/// // Keep a record of the errors, while clients are sent everything.
+/// let errors = FileSink::open("my-app-errors.defmt")?;
+/// defmt_logger_tcp::add_filtered_transport(errors, Filter::new().level(Level::Error));
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub fn add_filtered_transport<T>(transport: T, filter: Filter)
+where
+    T: Transport<Error = io::Error> + Send + 'static,
+{
+    // The writer thread starts with the defaults, and takes up the settings
+    // of the first server to start.
+    if let Err(e) = spawn(None) {
+        error::report(e);
+    }
+
+    let mut client = Client::new(Box::new(transport), None);
+    client.set_filter(filter);
+    PENDING_CLIENTS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(client);
+    notify();
+}
+
+/// Starts the writer thread, applying the settings from `config`.
+///
+/// Only the first server to start configures the writer. The buffer capacity
+/// can only be changed before the writer thread starts, so a transport added
+/// earlier leaves it at the default.
+pub(crate) fn start(config: &ServerConfig) -> Result<(), Error> {
+    RING.set_policy(config.overflow_policy);
+    spawn(Some(config))
+}
+
+fn spawn(config: Option<&ServerConfig>) -> Result<(), Error> {
+    /// Whether a server has configured the writer thread.
+    static CONFIGURED: Mutex<bool> = Mutex::new(false);
+
+    let mut configured = CONFIGURED.lock().unwrap_or_else(PoisonError::into_inner);
+    if WRITER.get().is_some() {
+        if let (Some(config), false) = (config, *configured) {
+            *RECONFIGURE.lock().unwrap_or_else(PoisonError::into_inner) =
+                Some(WriterConfig::new(config));
+            *configured = true;
+            notify();
+        }
+        return Ok(());
+    }
+
+    *configured = config.is_some();
+    let default = ServerConfig::default();
+    let config = config.unwrap_or(&default);
+
+    protocol::start_session();
+    let writer_config = WriterConfig::new(config);
+    let writer = thread::Builder::new()
+        .name("defmt-logger-tcp".into())
+        .spawn(move || {
+            // Wait for the buffer to be set up.
+            while WRITER.get().is_none() {
+                thread::park();
+            }
+            run(writer_config)
+        })
+        .map_err(Error::Spawn)?;
+
+    // The buffer is only set up once the thread is sure to run, as frames
+    // pile up in it from then on.
+    LOCK.lock();
+    // SAFETY: the frame lock is held and the writer isn't running yet.
+    unsafe {
+        RING.resize(config.buffer_capacity);
+        RING.start_consumer();
+        LOCK.unlock();
+    }
+
+    let _ = WRITER.set(writer.thread().clone());
+    writer.thread().unpark();
+    Ok(())
+}
+
+/// Waits up to `timeout` for every frame committed so far to be sent.
+pub(crate) fn wait_sent(timeout: Duration) {
+    if WRITER.get().is_none() {
+        return;
+    }
+
+    let target = RING.head();
+    let deadline = Instant::now() + timeout;
+    while RING.is_behind(SENT.load(Ordering::Acquire), target) && Instant::now() < deadline {
+        notify();
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Wakes the writer thread after a frame has been committed, or a client
+/// has connected.
+pub(crate) fn notify() {
+    if let Some(writer) = WRITER.get() {
+        writer.unpark();
+    }
+}
+
+fn run(mut config: WriterConfig) {
+    let mut history = History::new(config.history_bytes, config.history_frames);
+    let mut clients: Vec<Client> = Vec::new();
+    let mut encoder = Encoder::new();
+    let mut frame = Vec::new();
+    let mut encoded = Vec::new();
+
+    loop {
+        if let Some(update) = RECONFIGURE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+        {
+            history.set_limits(update.history_bytes, update.history_frames);
+            config = update;
+        }
+
+        // Clients only join at a frame boundary, caught up with the history.
+        let pending = mem::take(
+            &mut *PENDING_CLIENTS
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
+        for mut client in pending {
+            for recent in history.frames.iter() {
+                if recent.raw.as_ref().is_none_or(|raw| client.wants(raw)) {
+                    client.push_history(&recent.encoded);
+                }
+            }
+            clients.push(client);
+        }
+
+        // Requests are handled first, so a new filter applies to the frames
+        // popped next.
+        clients.retain_mut(|client| {
+            keep(client, |client| {
+                answer(client, &config).map_err(Error::Disconnected)
+            })
+        });
+
+        // Drops are reported before the frames buffered since, which is
+        // about where they happened.
+        let dropped = RING.take_dropped();
+        if dropped > 0 {
+            let dropped = u32::try_from(dropped).unwrap_or(u32::MAX);
+            stats::frames_dropped(dropped);
+            let report = gap::dropped(dropped);
+            history.push(None, &report);
+            for client in clients.iter_mut() {
+                client.push_dropped(dropped, &report);
+            }
+        }
+
+        // Frames logged meanwhile are left for the next pass, so clients are
+        // still sent frames while logging outpaces the writer.
+        let end = RING.head();
+        while RING.is_behind(RING.tail(), end)
+            && !backpressure(&clients, config.queue_limit)
+            && RING.pop(&mut frame)
+        {
+            let encoding = Instant::now();
+            encoded.clear();
+            encoder.start_frame(|bytes| encoded.extend_from_slice(bytes));
+            encoder.write(&frame, |bytes| encoded.extend_from_slice(bytes));
+            encoder.end_frame(|bytes| encoded.extend_from_slice(bytes));
+            stats::encoded(encoding.elapsed());
+
+            history.push(Some(&frame), &encoded);
+
+            let blocking = RING.policy() == OverflowPolicy::Block;
+            for client in clients.iter_mut() {
+                if !client.wants(&frame) {
+                    continue;
+                }
+                // Clients that are disconnected instead are left to catch up
+                // on the next send.
+                if !blocking
+                    && client.slow_client_policy() == SlowClientPolicy::SkipFrames
+                    && client.backlog() + encoded.len() > client.queue_limit(config.queue_limit)
+                {
+                    let skipped = client.skip_queued();
+                    stats::frames_dropped(skipped);
+                }
+                client.push(&encoded);
+            }
+        }
+
+        let popped = RING.tail();
+
+        clients.retain_mut(|client| {
+            let sent = keep(client, |client| {
+                client.send().map_err(Error::Disconnected)?;
+                if client.is_stalled(config.stall_timeout) {
+                    return Err(Error::Stalled);
+                }
+                if client.slow_client_policy() == SlowClientPolicy::Disconnect
+                    && client.backlog() > client.queue_limit(config.queue_limit)
+                {
+                    return Err(Error::FellBehind);
+                }
+                Ok(())
+            });
+            sent && !client.is_closed()
+        });
+
+        if clients.iter().all(Client::is_idle) {
+            SENT.store(popped, Ordering::Release);
+            thread::park_timeout(IDLE_TIMEOUT);
+        } else {
+            thread::park_timeout(RETRY_TIMEOUT);
+        }
+    }
+}
+
+/// Runs `f` on a client, returning `false` if the client should be dropped
+/// because it failed, after reporting why.
+///
+/// A transport that panics is dropped rather than taking the writer thread,
+/// and with it all logging, down too.
+fn keep(client: &mut Client, f: impl FnOnce(&mut Client) -> Result<(), Error>) -> bool {
+    match panic::catch_unwind(AssertUnwindSafe(|| f(client))) {
+        Ok(Ok(())) => true,
+        Ok(Err(e)) => {
+            error::report(e);
+            false
+        }
+        Err(_) => {
+            error::report(Error::TransportPanicked);
+            false
+        }
+    }
+}
+
+/// Handles a client's requests, and queues the next part of the defmt table
+/// while it is being sent.
+///
+/// Table frames are only queued while the client is at most half full, so
+/// they are interleaved with log frames rather than crowding them out.
+fn answer(client: &mut Client, config: &WriterConfig) -> io::Result<()> {
+    for request in client.receive()? {
+        match request {
+            Request::Table => {
+                if config.serve_table && !protocol::table().is_empty() {
+                    client.table_chunk = Some(0);
+                } else {
+                    client.push_control(&protocol::table_unavailable());
+                }
+            }
+            Request::Filter(filter) => client.set_filter(filter),
+        }
+    }
+
+    while let Some(index) = client.table_chunk {
+        if client.queued_bytes() >= client.queue_limit(config.queue_limit) / 2 {
+            break;
+        }
+
+        let table = protocol::table();
+        client.push_control(&table[index]);
+        client.table_chunk = Some(index + 1).filter(|&next| next < table.len());
+    }
+    Ok(())
+}
+
+/// Whether frames should be left in the ring buffer, so that logging blocks
+/// until every client has room for them. Clients that are disconnected when
+/// they fall behind never hold logging up.
+fn backpressure(clients: &[Client], queue_limit: usize) -> bool {
+    RING.policy() == OverflowPolicy::Block
+        && clients.iter().any(|client| {
+            client.slow_client_policy() == SlowClientPolicy::SkipFrames
+                && client.backlog() >= client.queue_limit(queue_limit)
+        })
+}
+
+struct WriterConfig {
+    queue_limit: usize,
+    stall_timeout: Duration,
+    serve_table: bool,
+    history_bytes: usize,
+    history_frames: usize,
+}
+
+impl WriterConfig {
+    fn new(config: &ServerConfig) -> Self {
+        Self {
+            queue_limit: config.buffer_capacity,
+            stall_timeout: config.write_timeout,
+            serve_table: config.serve_table,
+            history_bytes: config.history_bytes,
+            history_frames: config.history_frames,
+        }
+    }
+}
+
+/// The most recent encoded frames, replayed to clients when they connect.
+struct History {
+    frames: VecDeque<Recent>,
+    bytes: usize,
+    max_bytes: usize,
+    max_frames: usize,
+}
+
+impl History {
+    fn new(max_bytes: usize, max_frames: usize) -> Self {
+        Self {
+            frames: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+            max_frames,
+        }
+    }
+
+    /// Adds an encoded frame, with its raw content unless it is a drop
+    /// report that every client is sent.
+    fn push(&mut self, raw: Option<&[u8]>, encoded: &[u8]) {
+        if encoded.len() > self.max_bytes || self.max_frames == 0 {
+            return;
+        }
+
+        self.trim(encoded.len(), 1);
+        self.bytes += encoded.len();
+        self.frames.push_back(Recent {
+            raw: raw.map(<[u8]>::to_vec),
+            encoded: encoded.to_vec(),
+        });
+    }
+
+    fn set_limits(&mut self, max_bytes: usize, max_frames: usize) {
+        self.max_bytes = max_bytes;
+        self.max_frames = max_frames;
+        self.trim(0, 0);
+    }
+
+    /// Drops the oldest frames until there is room for `frames` more frames
+    /// of `bytes` in all.
+    fn trim(&mut self, bytes: usize, frames: usize) {
+        while self.frames.len() + frames > self.max_frames || self.bytes + bytes > self.max_bytes {
+            let Some(oldest) = self.frames.pop_front() else {
+                break;
+            };
+            self.bytes -= oldest.encoded.len();
+        }
+    }
+}
+
+/// A frame in the history.
+struct Recent {
+    /// The raw frame, for filtering, or `None` for a drop report.
+    raw: Option<Vec<u8>>,
+    encoded: Vec<u8>,
+}
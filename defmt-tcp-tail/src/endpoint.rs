@@ -0,0 +1,93 @@
+//! The addresses logs are read from.
+
+use std::{
+    convert::Infallible,
+    fmt,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    str::FromStr,
+};
+
+/// The port `defmt-logger-tcp` listens on by default.
+const DEFAULT_PORT: u16 = 19021;
+
+/// A connection to a server, which requests can be written to.
+pub trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+/// A server to read logs from, either `host[:port]` or `unix:<path>`.
+#[derive(Debug, Clone)]
+pub enum Endpoint {
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Connects to the server.
+    pub fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Self::Tcp(addr) => Ok(Box::new(TcpStream::connect(addr.as_str())?)),
+            #[cfg(unix)]
+            Self::Unix(path) => Ok(Box::new(std::os::unix::net::UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Self::Unix(_) => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Unix domain sockets aren't supported on this platform",
+            )),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()));
+        }
+
+        let addr = match s.rsplit_once(':') {
+            // `host:4000` or `[::1]:4000`.
+            Some((host, port))
+                if port.parse::<u16>().is_ok() && (host.ends_with(']') || !host.contains(':')) =>
+            {
+                s.to_string()
+            }
+            // A bare IPv6 address.
+            Some(_) if !s.starts_with('[') => format!("[{s}]:{DEFAULT_PORT}"),
+            _ => format!("{s}:{DEFAULT_PORT}"),
+        };
+        Ok(Self::Tcp(addr))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => f.write_str(addr),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> String {
+        s.parse::<Endpoint>().unwrap().to_string()
+    }
+
+    #[test]
+    fn parses_endpoints() {
+        assert_eq!(parse("localhost"), "localhost:19021");
+        assert_eq!(parse("localhost:4000"), "localhost:4000");
+        assert_eq!(parse("192.0.2.1:4000"), "192.0.2.1:4000");
+        assert_eq!(parse("::1"), "[::1]:19021");
+        assert_eq!(parse("[::1]"), "[::1]:19021");
+        assert_eq!(parse("[::1]:4000"), "[::1]:4000");
+        assert_eq!(parse("unix:/tmp/my-app.sock"), "unix:/tmp/my-app.sock");
+    }
+}
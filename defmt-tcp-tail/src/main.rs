@@ -0,0 +1,441 @@
+//! Follows the logs served by `defmt-logger-tcp`, and decodes them against
+//! the executable that logged them, or the defmt table the server sends.
+
+mod endpoint;
+mod output;
+mod protocol;
+
+use anyhow::{anyhow, Context};
+use clap::{Parser, ValueEnum};
+use defmt_decoder::{DecodeError, StreamDecoder, Table};
+use std::{
+    fs,
+    io::{self, IsTerminal},
+    path::PathBuf,
+    process, thread,
+    time::Duration,
+};
+
+use crate::{
+    endpoint::{Endpoint, Stream},
+    output::{Filter, Format, Level, Printer},
+    protocol::{Assembly, Control, FrameSplitter, Handshake, TableAssembler, PROTOCOL_VERSION},
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Args {
+    /// The executable whose logs are decoded. Without it, each server is
+    /// asked for its defmt table, which it must be configured to serve.
+    #[arg(short, long)]
+    elf: Option<PathBuf>,
+
+    /// The servers to follow, as `host[:port]` or `unix:<path>`.
+    #[arg(default_value = "localhost:19021")]
+    endpoints: Vec<Endpoint>,
+
+    /// Only print frames at this level or above.
+    #[arg(short, long, value_enum)]
+    level: Option<Level>,
+
+    /// Only print frames from this module or its submodules, may be
+    /// repeated.
+    #[arg(short, long = "module", value_name = "MODULE")]
+    modules: Vec<String>,
+
+    /// Don't print frames from this module or its submodules, may be
+    /// repeated.
+    #[arg(short = 'x', long = "exclude-module", value_name = "MODULE")]
+    excluded: Vec<String>,
+
+    /// Print each frame as a JSON object.
+    #[arg(long)]
+    json: bool,
+
+    /// When to colour the log levels.
+    #[arg(long, value_enum, default_value_t = Color::Auto)]
+    color: Color,
+
+    /// Exit when a server disconnects, instead of reconnecting.
+    #[arg(long)]
+    no_reconnect: bool,
+
+    /// How long to wait between connection attempts, in milliseconds.
+    #[arg(long, value_name = "MS", default_value_t = 1000)]
+    retry_interval: u64,
+
+    /// Decode with the ELF given even when a server says it is running a
+    /// different executable, instead of refusing to.
+    #[arg(long)]
+    allow_elf_mismatch: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
+    let elf = match &args.elf {
+        Some(path) => {
+            let elf = fs::read(path).with_context(|| format!("failed to read {path:?}"))?;
+            let table = Table::parse(&elf)?.ok_or_else(|| anyhow!("{path:?} has no defmt data"))?;
+            Some((path, elf, table))
+        }
+        None => None,
+    };
+    // Without debug info frames can't be filtered by module.
+    let locations = elf
+        .as_ref()
+        .and_then(|(_, elf, table)| table.get_locations(elf).ok());
+    if locations.is_none() && !(args.modules.is_empty() && args.excluded.is_empty()) {
+        match &elf {
+            Some((path, ..)) => {
+                eprintln!("warning: no location info in {path:?}, so no module matches")
+            }
+            None => eprintln!("warning: no location info without an ELF, so no module matches"),
+        }
+    }
+
+    let filter = Filter {
+        level: args.level,
+        modules: args.modules,
+        excluded: args.excluded,
+    };
+    let format = if args.json {
+        Format::Json
+    } else {
+        let colored = match args.color {
+            Color::Auto => io::stdout().is_terminal(),
+            Color::Always => true,
+            Color::Never => false,
+        };
+        Format::Text { colored }
+    };
+    let printer = Printer {
+        filter: &filter,
+        format,
+        locations: locations.as_ref(),
+        prefix: args.endpoints.len() > 1,
+    };
+    let follow = Follow {
+        table: elf.as_ref().map(|(_, _, table)| table),
+        elf_id: elf.as_ref().map(|(_, elf, _)| protocol::elf_id(elf)),
+        allow_elf_mismatch: args.allow_elf_mismatch,
+        printer: &printer,
+        reconnect: !args.no_reconnect,
+        retry_interval: Duration::from_millis(args.retry_interval),
+    };
+
+    let failed = thread::scope(|scope| {
+        let threads: Vec<_> = args
+            .endpoints
+            .iter()
+            .map(|endpoint| scope.spawn(|| follow.run(endpoint)))
+            .collect();
+
+        threads
+            .into_iter()
+            .map(|thread| thread.join())
+            .filter(|result| !matches!(result, Ok(Ok(()))))
+            .count()
+    });
+
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// Follows one endpoint at a time, with the shared settings.
+struct Follow<'a> {
+    /// The table of the ELF given, or `None` to fetch each server's.
+    table: Option<&'a Table>,
+    /// The id of the ELF given, to compare with the one in handshakes.
+    elf_id: Option<Vec<u8>>,
+    allow_elf_mismatch: bool,
+    printer: &'a Printer<'a>,
+    reconnect: bool,
+    retry_interval: Duration,
+}
+
+impl Follow<'_> {
+    fn run(&self, endpoint: &Endpoint) -> io::Result<()> {
+        let mut waiting = false;
+        let mut session = None;
+        loop {
+            match endpoint.connect() {
+                Ok(stream) => {
+                    if waiting {
+                        eprintln!("connected to {endpoint}");
+                        waiting = false;
+                    }
+                    if let Err(e) = self.read(endpoint, stream, &mut session) {
+                        eprintln!("error: {e}");
+                        return Err(e);
+                    }
+                    eprintln!("disconnected from {endpoint}");
+                }
+                Err(e) if self.reconnect => {
+                    if !waiting {
+                        eprintln!("waiting for {endpoint}: {e}");
+                        waiting = true;
+                    }
+                }
+                Err(e) => {
+                    eprintln!("failed to connect to {endpoint}: {e}");
+                    return Err(e);
+                }
+            }
+
+            if !self.reconnect {
+                return Ok(());
+            }
+            thread::sleep(self.retry_interval);
+        }
+    }
+
+    /// Decodes and prints frames until the connection is closed, or fails if
+    /// the server is running a different executable.
+    ///
+    /// Each connection gets its own decoder, so a restarted server's stream
+    /// is decoded from the start.
+    fn read(
+        &self,
+        endpoint: &Endpoint,
+        mut stream: Box<dyn Stream>,
+        session: &mut Option<Handshake>,
+    ) -> io::Result<()> {
+        // Have the server drop what would be filtered out here anyway. Servers
+        // that don't filter ignore the request.
+        if let Some(level) = self.printer.filter.level {
+            let request = format!("filter {}\n", level.name());
+            if send_request(endpoint, &mut *stream, &request).is_err() {
+                return Ok(());
+            }
+        }
+
+        let mut frames = FrameSplitter::default();
+        // Log frames received before the server's table.
+        let mut early = Vec::new();
+        let fetched;
+        let table = match self.table {
+            Some(table) => table,
+            None => {
+                match self.fetch_table(endpoint, &mut *stream, &mut frames, &mut early, session)? {
+                    Some(table) => {
+                        fetched = table;
+                        &fetched
+                    }
+                    None => return Ok(()),
+                }
+            }
+        };
+
+        let mut decoder = table.new_stream_decoder();
+        for frame in &early {
+            decoder.received(frame);
+            decoder.received(&[0]);
+        }
+        self.print(endpoint, &mut *decoder);
+
+        while receive(endpoint, &mut *stream, &mut frames, |frame| {
+            match Control::parse(frame) {
+                Some(Control::Handshake(handshake)) => {
+                    return self.handshake(endpoint, handshake, session);
+                }
+                Some(Control::Table(_) | Control::Unknown) => {}
+                None => {
+                    decoder.received(frame);
+                    decoder.received(&[0]);
+                    self.print(endpoint, &mut *decoder);
+                }
+            }
+            Ok(())
+        })? {}
+        Ok(())
+    }
+
+    /// Asks the server for its defmt table, and reads until it has all been
+    /// received, keeping the log frames received meanwhile in `early`.
+    ///
+    /// Returns `None` if the connection is closed first.
+    fn fetch_table(
+        &self,
+        endpoint: &Endpoint,
+        stream: &mut dyn Stream,
+        frames: &mut FrameSplitter,
+        early: &mut Vec<Vec<u8>>,
+        session: &mut Option<Handshake>,
+    ) -> io::Result<Option<Table>> {
+        let mut assembler = TableAssembler::new();
+        let mut assembly = Assembly::Incomplete;
+        if send_request(endpoint, stream, "table\n").is_err() {
+            return Ok(None);
+        }
+
+        while assembly != Assembly::Complete {
+            let open = receive(endpoint, stream, frames, |frame| {
+                match Control::parse(frame) {
+                    Some(Control::Handshake(handshake)) => {
+                        return self.handshake(endpoint, handshake, session);
+                    }
+                    Some(Control::Table(chunk)) if assembly != Assembly::Complete => {
+                        assembly = assembler.push(chunk);
+                    }
+                    Some(Control::Table(_) | Control::Unknown) => {}
+                    None => early.push(frame.to_vec()),
+                }
+                Ok(())
+            })?;
+            if !open {
+                return Ok(None);
+            }
+
+            match assembly {
+                Assembly::Unavailable => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::Unsupported,
+                        format!(
+                            "{endpoint} doesn't serve its defmt table, pass the ELF with --elf"
+                        ),
+                    ));
+                }
+                // Frames were dropped because this client fell behind.
+                Assembly::Missed => {
+                    assembly = Assembly::Incomplete;
+                    if send_request(endpoint, stream, "table\n").is_err() {
+                        return Ok(None);
+                    }
+                }
+                Assembly::Incomplete | Assembly::Complete => {}
+            }
+        }
+
+        let table = assembler
+            .into_elf()
+            .and_then(|elf| Table::parse(&elf).ok().flatten())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{endpoint} sent a malformed defmt table"),
+                )
+            })?;
+        Ok(Some(table))
+    }
+
+    /// Prints every frame the decoder has received.
+    fn print(&self, endpoint: &Endpoint, decoder: &mut dyn StreamDecoder) {
+        loop {
+            match decoder.decode() {
+                Ok(frame) => {
+                    if let Err(e) = self.printer.print(endpoint, &frame) {
+                        exit_on_output_error(e);
+                    }
+                }
+                Err(DecodeError::UnexpectedEof) => break,
+                // The decoder skips to the next frame.
+                Err(DecodeError::Malformed) => {}
+            }
+        }
+    }
+
+    /// Reports a restart when a new session starts, and checks the server
+    /// is running the ELF given.
+    fn handshake(
+        &self,
+        endpoint: &Endpoint,
+        handshake: Handshake,
+        session: &mut Option<Handshake>,
+    ) -> io::Result<()> {
+        let mismatch = self
+            .elf_id
+            .as_ref()
+            .is_some_and(|elf_id| !handshake.elf_id.is_empty() && handshake.elf_id != *elf_id);
+        if mismatch && !self.allow_elf_mismatch {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{endpoint} is running a different executable than the ELF given, \
+                     pass --allow-elf-mismatch to decode its logs anyway"
+                ),
+            ));
+        }
+
+        if session
+            .as_ref()
+            .is_some_and(|session| session.same_session(&handshake))
+        {
+            return Ok(());
+        }
+
+        if session.is_some() {
+            eprintln!("{endpoint} restarted, now process {}", handshake.pid);
+        }
+        if handshake.version > PROTOCOL_VERSION {
+            eprintln!(
+                "warning: {endpoint} uses protocol version {}, this client only knows {PROTOCOL_VERSION}",
+                handshake.version
+            );
+        }
+        if mismatch {
+            eprintln!("warning: {endpoint} is running a different executable than the ELF given");
+        }
+        *session = Some(handshake);
+        Ok(())
+    }
+}
+
+/// Reads the next bytes from the stream, calling `frame` with each frame they
+/// complete.
+///
+/// Returns `false` once the connection is closed, and stops at the first
+/// error `frame` returns.
+fn receive(
+    endpoint: &Endpoint,
+    stream: &mut dyn Stream,
+    frames: &mut FrameSplitter,
+    mut frame: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<bool> {
+    let mut buffer = [0; 4096];
+    let read = loop {
+        match stream.read(&mut buffer) {
+            Ok(0) => return Ok(false),
+            Ok(read) => break read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                eprintln!("failed to read from {endpoint}: {e}");
+                return Ok(false);
+            }
+        }
+    };
+
+    let mut result = Ok(());
+    frames.push(&buffer[..read], |encoded| {
+        if result.is_ok() {
+            result = frame(encoded);
+        }
+    });
+    result.map(|()| true)
+}
+
+/// Sends the server a request line.
+fn send_request(endpoint: &Endpoint, stream: &mut dyn Stream, request: &str) -> io::Result<()> {
+    stream.write_all(request.as_bytes()).inspect_err(|e| {
+        eprintln!("failed to send a request to {endpoint}: {e}");
+    })
+}
+
+/// Exits once stdout can't be written, quietly if the reader went away.
+fn exit_on_output_error(e: io::Error) -> ! {
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        process::exit(0);
+    }
+
+    eprintln!("failed to write to stdout: {e}");
+    process::exit(1);
+}
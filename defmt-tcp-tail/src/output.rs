@@ -0,0 +1,123 @@
+//! Filtering and printing decoded frames.
+
+use clap::ValueEnum;
+use defmt_decoder::{Frame, Locations};
+use serde_json::json;
+use std::io::{self, Write};
+
+use crate::endpoint::Endpoint;
+
+/// A log level, ordered from least to most severe.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Level {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    /// Returns the name servers know the level by.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Trace => "trace",
+            Self::Debug => "debug",
+            Self::Info => "info",
+            Self::Warn => "warn",
+            Self::Error => "error",
+        }
+    }
+
+    fn of(frame: &Frame) -> Option<Self> {
+        Some(match frame.level()?.as_str() {
+            "trace" => Self::Trace,
+            "debug" => Self::Debug,
+            "info" => Self::Info,
+            "warn" => Self::Warn,
+            _ => Self::Error,
+        })
+    }
+}
+
+/// Which frames are printed.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// The least severe level printed. Frames without a level, from
+    /// `defmt::println!`, are always printed.
+    pub level: Option<Level>,
+    /// Module path prefixes to print, or empty for every module.
+    pub modules: Vec<String>,
+    /// Module path prefixes not to print.
+    pub excluded: Vec<String>,
+}
+
+impl Filter {
+    fn matches(&self, level: Option<Level>, module: Option<&str>) -> bool {
+        if let (Some(min), Some(level)) = (self.level, level) {
+            if level < min {
+                return false;
+            }
+        }
+
+        let module = module.unwrap_or_default();
+        let in_module = |prefix: &String| {
+            module
+                .strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+        };
+        (self.modules.is_empty() || self.modules.iter().any(in_module))
+            && !self.excluded.iter().any(in_module)
+    }
+}
+
+/// How frames are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Text { colored: bool },
+    Json,
+}
+
+/// Prints the frames that pass a filter to stdout.
+pub struct Printer<'a> {
+    pub filter: &'a Filter,
+    pub format: Format,
+    pub locations: Option<&'a Locations>,
+    /// Whether lines start with the endpoint they came from.
+    pub prefix: bool,
+}
+
+impl Printer<'_> {
+    pub fn print(&self, endpoint: &Endpoint, frame: &Frame) -> io::Result<()> {
+        let level = Level::of(frame);
+        let location = self
+            .locations
+            .and_then(|locations| locations.get(&frame.index()));
+        let module = location.map(|location| location.module.as_str());
+        if !self.filter.matches(level, module) {
+            return Ok(());
+        }
+
+        let mut stdout = io::stdout().lock();
+        match self.format {
+            Format::Text { colored } => {
+                if self.prefix {
+                    write!(stdout, "[{endpoint}] ")?;
+                }
+                writeln!(stdout, "{}", frame.display(colored))
+            }
+            Format::Json => {
+                let line = json!({
+                    "endpoint": endpoint.to_string(),
+                    "timestamp": frame.display_timestamp().map(|timestamp| timestamp.to_string()),
+                    "level": frame.level().map(|level| level.as_str()),
+                    "module": module,
+                    "file": location.map(|location| location.file.display().to_string()),
+                    "line": location.map(|location| location.line),
+                    "message": frame.display_message().to_string(),
+                });
+                writeln!(stdout, "{line}")
+            }
+        }
+    }
+}
@@ -0,0 +1,459 @@
+//! The control frames `defmt-logger-tcp` sends alongside the log frames.
+
+use object::{
+    write::{self, SectionKind, StandardSegment, Symbol, SymbolSection},
+    Architecture, BinaryFormat, Endianness, Object, ObjectSection, ObjectSymbol, SymbolFlags,
+    SymbolKind, SymbolScope,
+};
+
+/// The string index control frames start with.
+const CONTROL_INDEX: u16 = 0xFFFF;
+
+/// The newest protocol version understood.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// The kind of the handshake control frame.
+const CONTROL_HANDSHAKE: u8 = 0;
+
+/// The kind of the control frames holding the defmt table.
+const CONTROL_TABLE: u8 = 1;
+
+/// The handshake a server sends before any frames.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Handshake {
+    pub version: u8,
+    pub pid: u32,
+    /// When the logger started, in microseconds since the Unix epoch.
+    pub session_start: u64,
+    pub elf_id: Vec<u8>,
+}
+
+impl Handshake {
+    /// Returns `true` if both handshakes come from the same run of a process.
+    pub fn same_session(&self, other: &Self) -> bool {
+        self.pid == other.pid && self.session_start == other.session_start
+    }
+
+    fn parse(fields: &[u8]) -> Option<Self> {
+        let (&version, fields) = fields.split_first()?;
+        let pid = u32::from_le_bytes(fields.get(..4)?.try_into().ok()?);
+        let session_start = u64::from_le_bytes(fields.get(4..12)?.try_into().ok()?);
+        let len = usize::from(*fields.get(12)?);
+        let elf_id = fields.get(13..13 + len)?.to_vec();
+
+        Some(Self {
+            version,
+            pid,
+            session_start,
+            elf_id,
+        })
+    }
+}
+
+/// A part of the defmt table, sent in response to a `table` request.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TableChunk {
+    pub index: u32,
+    /// The number of chunks, or `0` if the server doesn't serve its table.
+    pub count: u32,
+    pub data: Vec<u8>,
+}
+
+impl TableChunk {
+    fn parse(fields: &[u8]) -> Option<Self> {
+        let index = u32::from_le_bytes(fields.get(..4)?.try_into().ok()?);
+        let count = u32::from_le_bytes(fields.get(4..8)?.try_into().ok()?);
+        // Decoding pads the frame with zeros, so the data has its length.
+        let len = u32::from_le_bytes(fields.get(8..12)?.try_into().ok()?);
+        let data = fields.get(12..12 + usize::try_from(len).ok()?)?.to_vec();
+
+        Some(Self { index, count, data })
+    }
+}
+
+/// A control frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    Handshake(Handshake),
+    Table(TableChunk),
+    /// A kind of control frame added by a newer protocol version.
+    Unknown,
+}
+
+impl Control {
+    /// Parses an encoded frame, without its separator, returning `None` if
+    /// it is a log frame.
+    pub fn parse(encoded: &[u8]) -> Option<Self> {
+        let raw = rzcobs_decode(encoded)?;
+        let (index, raw) = raw.split_first_chunk::<2>()?;
+        if u16::from_le_bytes(*index) != CONTROL_INDEX {
+            return None;
+        }
+
+        match raw.split_first() {
+            Some((&CONTROL_HANDSHAKE, fields)) => {
+                Some(Handshake::parse(fields).map_or(Control::Unknown, Control::Handshake))
+            }
+            Some((&CONTROL_TABLE, fields)) => {
+                Some(TableChunk::parse(fields).map_or(Control::Unknown, Control::Table))
+            }
+            _ => Some(Control::Unknown),
+        }
+    }
+}
+
+/// Puts together the defmt table a server sends in chunks.
+#[derive(Debug, Default)]
+pub struct TableAssembler {
+    data: Vec<u8>,
+    /// The index of the next chunk expected, or `None` if a chunk was
+    /// missed and the table must be requested again.
+    next: Option<u32>,
+    count: u32,
+}
+
+/// What has been put together so far.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Assembly {
+    Incomplete,
+    /// A chunk was missed, so the table must be requested again. Chunks are
+    /// ignored until the table starts over.
+    Missed,
+    Complete,
+    /// The server doesn't serve its table.
+    Unavailable,
+}
+
+impl TableAssembler {
+    pub fn new() -> Self {
+        Self {
+            next: Some(0),
+            ..Self::default()
+        }
+    }
+
+    pub fn push(&mut self, chunk: TableChunk) -> Assembly {
+        if chunk.count == 0 {
+            return Assembly::Unavailable;
+        }
+
+        if chunk.index == 0 {
+            self.data.clear();
+            self.count = chunk.count;
+        } else if self.next != Some(chunk.index) || self.count != chunk.count {
+            let missed = self.next.is_some();
+            self.next = None;
+            return if missed {
+                Assembly::Missed
+            } else {
+                Assembly::Incomplete
+            };
+        }
+
+        self.data.extend_from_slice(&chunk.data);
+        self.next = Some(chunk.index + 1);
+        if chunk.index + 1 == self.count {
+            Assembly::Complete
+        } else {
+            Assembly::Incomplete
+        }
+    }
+
+    /// Builds an ELF file holding just the table, which `defmt-decoder` can
+    /// parse as if it were the executable's.
+    pub fn into_elf(self) -> Option<Vec<u8>> {
+        let mut table = self.data.as_slice();
+        let count = u32::from_le_bytes(take(&mut table, 4)?.try_into().ok()?);
+
+        let mut elf =
+            write::Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let section = elf.add_section(
+            elf.segment_name(StandardSegment::Data).to_vec(),
+            b".defmt".to_vec(),
+            SectionKind::UninitializedData,
+        );
+
+        let mut size = 0;
+        for _ in 0..count {
+            let in_defmt = take(&mut table, 1)?[0] == 1;
+            let address = u64::from_le_bytes(take(&mut table, 8)?.try_into().ok()?);
+            let len = u16::from_le_bytes(take(&mut table, 2)?.try_into().ok()?);
+            let name = take(&mut table, usize::from(len))?.to_vec();
+
+            if in_defmt {
+                size = size.max(address + 1);
+            }
+            elf.add_symbol(Symbol {
+                name,
+                value: address,
+                size: 0,
+                kind: SymbolKind::Data,
+                scope: SymbolScope::Linkage,
+                weak: false,
+                section: if in_defmt {
+                    SymbolSection::Section(section)
+                } else {
+                    SymbolSection::Absolute
+                },
+                flags: SymbolFlags::None,
+            });
+        }
+        elf.append_section_bss(section, size, 1);
+
+        elf.write().ok()
+    }
+}
+
+/// Splits the first `len` bytes off `bytes`.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    let (taken, rest) = bytes.split_at_checked(len)?;
+    *bytes = rest;
+    Some(taken)
+}
+
+/// Splits a stream into encoded frames at the `0` separators.
+#[derive(Debug, Default)]
+pub struct FrameSplitter {
+    partial: Vec<u8>,
+}
+
+impl FrameSplitter {
+    /// Adds received bytes, calling `frame` with each frame they complete.
+    pub fn push(&mut self, bytes: &[u8], mut frame: impl FnMut(&[u8])) {
+        let mut rest = bytes;
+        while let Some(end) = rest.iter().position(|&byte| byte == 0) {
+            if self.partial.is_empty() {
+                if end > 0 {
+                    frame(&rest[..end]);
+                }
+            } else {
+                self.partial.extend_from_slice(&rest[..end]);
+                frame(&self.partial);
+                self.partial.clear();
+            }
+            rest = &rest[end + 1..];
+        }
+        self.partial.extend_from_slice(rest);
+    }
+}
+
+/// Returns the id `defmt-logger-tcp` advertises for an ELF file: its GNU
+/// build-id, or a hash of its defmt table if it has none.
+pub fn elf_id(elf: &[u8]) -> Vec<u8> {
+    let Ok(file) = object::File::parse(elf) else {
+        return Vec::new();
+    };
+
+    if let Ok(Some(build_id)) = file.build_id() {
+        return build_id.to_vec();
+    }
+
+    let Some(section) = file.section_by_name(".defmt") else {
+        return Vec::new();
+    };
+    let hash = file
+        .symbols()
+        .filter(|symbol| symbol.section_index() == Some(section.index()))
+        .fold(FNV_OFFSET, |hash, symbol| {
+            let hash = fnv1a(hash, &symbol.address().to_le_bytes());
+            fnv1a(hash, symbol.name_bytes().unwrap_or_default())
+        });
+    hash.to_le_bytes().to_vec()
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Decodes an rzCOBS encoded frame, as `defmt-decoder` does.
+fn rzcobs_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    let mut raw = Vec::with_capacity(encoded.len());
+    let mut bytes = encoded.iter().rev().copied();
+    while let Some(byte) = bytes.next() {
+        match byte {
+            0 => return None,
+            0x01..=0x7f => {
+                for i in 0..7 {
+                    if byte & (1 << (6 - i)) == 0 {
+                        raw.push(bytes.next()?);
+                    } else {
+                        raw.push(0);
+                    }
+                }
+            }
+            0x80..=0xfe => {
+                raw.push(0);
+                for _ in 0..(byte & 0x7f) + 7 {
+                    raw.push(bytes.next()?);
+                }
+            }
+            0xff => {
+                for _ in 0..134 {
+                    raw.push(bytes.next()?);
+                }
+            }
+        }
+    }
+
+    raw.reverse();
+    Some(raw)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(index: u32, count: u32, data: &[u8]) -> TableChunk {
+        TableChunk {
+            index,
+            count,
+            data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn parses_handshakes() {
+        // As encoded by `defmt-logger-tcp`, without the separators.
+        let handshake = [255, 255, 1, 7, 100, 42, 125, 2, 171, 205, 99];
+        assert_eq!(
+            Control::parse(&handshake),
+            Some(Control::Handshake(Handshake {
+                version: 1,
+                pid: 7,
+                session_start: 42,
+                elf_id: vec![0xab, 0xcd],
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_table_chunks() {
+        // Decoding pads the trailing zero with more.
+        let table = [255, 255, 1, 1, 112, 3, 3, 110, 97, 98, 121];
+        assert_eq!(
+            Control::parse(&table),
+            Some(Control::Table(chunk(1, 3, b"ab\0")))
+        );
+
+        let unavailable = [255, 255, 1, 120, 127, 127];
+        assert_eq!(
+            Control::parse(&unavailable),
+            Some(Control::Table(chunk(0, 0, b"")))
+        );
+    }
+
+    #[test]
+    fn leaves_log_frames_alone() {
+        assert_eq!(Control::parse(&[3, 1, 2, 114]), None);
+        assert_eq!(Control::parse(&[]), None);
+    }
+
+    #[test]
+    fn splits_frames_across_reads() {
+        let mut splitter = FrameSplitter::default();
+        let mut frames = Vec::new();
+
+        splitter.push(&[0, 1, 2, 0, 3], |frame| frames.push(frame.to_vec()));
+        assert_eq!(frames, [vec![1, 2]]);
+
+        splitter.push(&[4, 0, 0, 5], |frame| frames.push(frame.to_vec()));
+        assert_eq!(frames, [vec![1, 2], vec![3, 4]]);
+
+        splitter.push(&[0], |frame| frames.push(frame.to_vec()));
+        assert_eq!(frames, [vec![1, 2], vec![3, 4], vec![5]]);
+    }
+
+    #[test]
+    fn assembles_tables() {
+        let mut assembler = TableAssembler::new();
+        assert_eq!(assembler.push(chunk(0, 2, b"ab")), Assembly::Incomplete);
+        assert_eq!(assembler.push(chunk(1, 2, b"cd")), Assembly::Complete);
+        assert_eq!(assembler.data, b"abcd");
+    }
+
+    #[test]
+    fn notices_missed_chunks() {
+        let mut assembler = TableAssembler::new();
+        assert_eq!(assembler.push(chunk(0, 3, b"ab")), Assembly::Incomplete);
+        assert_eq!(assembler.push(chunk(2, 3, b"ef")), Assembly::Missed);
+        // Only reported once, until the table starts over.
+        assert_eq!(assembler.push(chunk(2, 3, b"ef")), Assembly::Incomplete);
+
+        assert_eq!(assembler.push(chunk(0, 2, b"ab")), Assembly::Incomplete);
+        assert_eq!(assembler.push(chunk(1, 2, b"cd")), Assembly::Complete);
+        assert_eq!(assembler.data, b"abcd");
+    }
+
+    #[test]
+    fn reports_unavailable_tables() {
+        let mut assembler = TableAssembler::new();
+        assert_eq!(assembler.push(chunk(0, 0, b"")), Assembly::Unavailable);
+    }
+
+    /// Builds an ELF from a table with one log statement, as the logger
+    /// would send it.
+    fn table_elf() -> Vec<u8> {
+        let name = br#"{"package":"my-app","tag":"defmt_info","data":"hi","disambiguator":"1","crate_name":"my_app"}"#;
+        let mut table = 3_u32.to_le_bytes().to_vec();
+        for (in_defmt, address, name) in [
+            (1, 1_u64, &name[..]),
+            (0, 0, b"_defmt_version_ = 4"),
+            (0, 0, b"_defmt_encoding_ = rzcobs"),
+        ] {
+            table.push(in_defmt);
+            table.extend_from_slice(&address.to_le_bytes());
+            table.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            table.extend_from_slice(name);
+        }
+
+        let mut assembler = TableAssembler::new();
+        assert_eq!(assembler.push(chunk(0, 1, &table)), Assembly::Complete);
+        assembler.into_elf().unwrap()
+    }
+
+    #[test]
+    fn builds_a_decodable_elf() {
+        let elf = table_elf();
+        let table = defmt_decoder::Table::parse(&elf).unwrap().unwrap();
+        assert_eq!(table.indices().collect::<Vec<_>>(), [1]);
+    }
+
+    #[test]
+    fn matches_the_loggers_protocol() {
+        assert_eq!(CONTROL_INDEX, defmt_logger_tcp::CONTROL_INDEX);
+        assert_eq!(CONTROL_HANDSHAKE, defmt_logger_tcp::CONTROL_HANDSHAKE);
+        assert_eq!(CONTROL_TABLE, defmt_logger_tcp::CONTROL_TABLE);
+        assert_eq!(PROTOCOL_VERSION, defmt_logger_tcp::PROTOCOL_VERSION);
+    }
+
+    #[test]
+    fn matches_the_loggers_elf_ids() {
+        let mut build_id =
+            write::Object::new(BinaryFormat::Elf, Architecture::X86_64, Endianness::Little);
+        let section = build_id.add_section(
+            Vec::new(),
+            b".note.gnu.build-id".to_vec(),
+            SectionKind::Note,
+        );
+        // The name and descriptor sizes, `NT_GNU_BUILD_ID`, then each of them.
+        let note = [
+            &4_u32.to_le_bytes(),
+            &8_u32.to_le_bytes(),
+            &3_u32.to_le_bytes(),
+            &b"GNU\0"[..],
+            b"12345678",
+        ];
+        build_id.append_section_data(section, &note.concat(), 4);
+        let build_id = build_id.write().unwrap();
+
+        assert_eq!(elf_id(&build_id), b"12345678");
+        for elf in [build_id, table_elf(), b"not an elf".to_vec()] {
+            assert_eq!(elf_id(&elf), defmt_logger_tcp::elf_id(&elf));
+        }
+        assert_eq!(elf_id(&table_elf()).len(), 8);
+    }
+}
@@ -6,6 +6,11 @@ fn main() {
         println!("cargo:rustc-link-search=/usr/lib/{target_arch}-linux-gnu/ldscripts");
         println!("cargo:rustc-link-arg=-Telf_{target_arch}.x");
 
+        // The linker scripts above are GNU ld scripts, so don't use rust-lld,
+        // and the default script is the one for position dependent binaries.
+        println!("cargo:rustc-link-arg=-fuse-ld=bfd");
+        println!("cargo:rustc-link-arg=-no-pie");
+
         // Add the defmt strings section to the elf file.
         println!("cargo:rustc-link-arg=-Tdefmt.x");
 
@@ -5,14 +5,15 @@ use std::time::{Duration, SystemTime};
 fn main() {
     thread::spawn(defmt_logger_tcp::run);
 
-    // Allow some time for the logger to attach.
-    // Use: `defmt-print -e ./target/debug/simple tcp`
-    thread::sleep(Duration::from_secs(10));
-
     let now = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs();
 
     info!("The current Unix timestamp is: {}", now);
+
+    // Recent logs are replayed to clients when they connect, so allow some
+    // time for the logger to attach before exiting.
+    // Use: `defmt-print -e ./target/debug/simple tcp`
+    thread::sleep(Duration::from_secs(10));
 }